@@ -1,17 +1,40 @@
 use base64::{engine::general_purpose::STANDARD, Engine as _};
-use hound::{SampleFormat, WavSpec};
-use rodio::Decoder;
-use std::io::{BufWriter, Cursor, Write};
-use std::{collections::HashMap, process::Stdio};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tauri::{AppHandle, Emitter};
 
-use crate::cmd_hidden;
 use crate::send_webhook_notification;
 
+mod cache;
+mod decode;
+mod dynamics;
+mod mixer;
+mod music;
+mod onset;
+mod playback;
+mod polyphony;
+mod resample;
+mod stretch;
+mod validate;
+mod waveform;
+mod writer;
+
+pub use cache::CacheInfo;
+pub use dynamics::Dynamics;
+pub use onset::{OffsetAnalysis, OffsetOptions};
+pub use playback::{play, seek, stop, unload};
+pub use polyphony::VoiceStealing;
+pub use resample::ResampleQuality;
+pub use validate::{validate_timeline, TimelineProblem};
+pub use waveform::Waveform;
+use writer::OutputWriter;
+
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct Sound {
     key: String,
-    data: String, // Base64-encoded audio data or path to audio file
+    data: String, // Base64-encoded audio data, `blob:<id>` or path to audio file
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
@@ -20,161 +43,807 @@ pub struct Timestamp {
     time: f64,   // Time in seconds after stream start
     volume: f32, // Volume level (0.0 - 1.0)
     #[serde(default = "default_rate")]
-    rate: f32, // Playback rate (1.0 is normal speed)
+    rate: f32, // Playback rate (1.0 is normal speed, 0.25 - 4.0)
+    #[serde(default)]
+    pan: f32, // Stereo position (-1.0 left, 0.0 center, 1.0 right)
+}
+
+impl Sound {
+    pub fn new(key: &str, data: String) -> Self {
+        Self {
+            key: key.to_string(),
+            data,
+        }
+    }
+}
+
+impl Timestamp {
+    /// A centered hit of `sound` at normal speed.
+    pub fn new(sound: &str, time: f64, volume: f32) -> Self {
+        Self {
+            sound: sound.to_string(),
+            time,
+            volume,
+            rate: 1.0,
+            pan: 0.0,
+        }
+    }
 }
 
 fn default_rate() -> f32 {
     1.0
 }
 
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct MusicTrack {
+    data: String, // Same forms as `Sound::data`
+    #[serde(default = "default_volume")]
+    volume: f32,
+    #[serde(default = "default_music_offset")]
+    offset: f64, // Start in seconds on the mix timeline; negative values trim the music
+}
+
+fn default_volume() -> f32 {
+    1.0
+}
+
+fn default_music_offset() -> f64 {
+    crate::ffmpeg::DEFAULT_MUSIC_OFFSET
+}
+
+/// Key of the music on the mix timeline, and the name of its stem. Sounds
+/// cannot use it.
+const MUSIC_KEY: &str = "music";
+
+/// Default sample rate of the mixer output.
+const SAMPLE_RATE: u32 = 48000;
+
+/// Range of sample rates accepted for the mixer output.
+const SAMPLE_RATES: std::ops::RangeInclusive<u32> = 8000..=192000;
+
+/// Blocks mixed in parallel before being written out.
+const PARALLEL_BLOCKS: usize = 64;
+
+/// Minimum time between two `audio-mixing-progress` events.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Fade-out in seconds of a voice cut by voice stealing.
+const STEAL_RELEASE: f64 = 0.005;
+
+/// Read the raw bytes of a sound from its uploaded blob, base64 data URL or
+/// file path.
+fn load_sound_data(key: &str, data: &str) -> Result<Vec<u8>, String> {
+    if let Some(id) = data.strip_prefix(crate::blob::BLOB_PREFIX) {
+        crate::blob::read(id).map_err(|e| format!("Error reading sound data for {}: {}", key, e))
+    } else if data.starts_with("data:") || data.contains(";base64,") {
+        let base64_data = data.split(",").last().unwrap_or(data);
+        STANDARD
+            .decode(base64_data)
+            .map_err(|e| format!("Error decoding base64 sound data for {}: {}", key, e))
+    } else {
+        std::fs::read(data)
+            .map_err(|e| format!("Error reading sound file {} for {}: {}", data, key, e))
+    }
+}
+
+/// Decode the encoded `data` of sound `key` into interleaved stereo samples
+/// at `target_rate`.
+fn decode_data(
+    key: &str,
+    data: Vec<u8>,
+    target_rate: u32,
+    quality: ResampleQuality,
+) -> Result<Vec<f32>, String> {
+    let decoded = decode::decode(key, data)?;
+    Ok(resample::resample_stereo(
+        &to_stereo(&decoded.samples, decoded.channels),
+        decoded.sample_rate as f64 / target_rate as f64,
+        quality,
+    ))
+}
+
+/// Decode a sound into interleaved stereo samples at `target_rate`, going
+/// through the decoded-sound cache in `cache_dir`.
+fn decode_sound(
+    sound: &Sound,
+    target_rate: u32,
+    quality: ResampleQuality,
+    cache_dir: Option<&Path>,
+) -> Result<Arc<Vec<f32>>, String> {
+    let data = load_sound_data(&sound.key, &sound.data)?;
+    let key = cache::key(&data, target_rate, quality);
+    if let Some(samples) = cache::get(cache_dir, &key) {
+        return Ok(samples);
+    }
+
+    let samples = decode_data(&sound.key, data, target_rate, quality)?;
+    Ok(cache::insert(cache_dir, &key, samples))
+}
+
+/// Convert interleaved samples with `channels` channels to interleaved stereo.
+///
+/// Mono is copied to both sides. For more than two channels, the first two
+/// are taken as front left/right, the third as center, the fourth (LFE) is
+/// dropped and the rest alternate between left and right, all at -3 dB.
+fn to_stereo(samples: &[f32], channels: usize) -> Vec<f32> {
+    match channels {
+        0 => Vec::new(),
+        1 => samples.iter().flat_map(|&s| [s, s]).collect(),
+        2 => samples.to_vec(),
+        _ => {
+            const ATTENUATION: f32 = std::f32::consts::FRAC_1_SQRT_2;
+            let mut stereo = Vec::with_capacity(samples.len() / channels * 2);
+            for frame in samples.chunks_exact(channels) {
+                let (mut left, mut right) = (frame[0], frame[1]);
+                for (i, &sample) in frame.iter().enumerate().skip(2) {
+                    match i {
+                        2 => {
+                            left += sample * ATTENUATION;
+                            right += sample * ATTENUATION;
+                        }
+                        3 => {}
+                        _ if i % 2 == 0 => left += sample * ATTENUATION,
+                        _ => right += sample * ATTENUATION,
+                    }
+                }
+                stereo.push(left);
+                stereo.push(right);
+            }
+            stereo
+        }
+    }
+}
+
+/// Optional behaviour of `mix_audio`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MixOptions {
+    /// Also write one WAV per sound key next to the output, named
+    /// `<output>-<key>.wav`.
+    pub stems: bool,
+    /// Resampler used for sample rate conversion and playback rates.
+    pub resample_quality: ResampleQuality,
+    /// Sample rate of the output in Hz, from 8 to 192 kHz.
+    pub sample_rate: u32,
+    /// Bits per sample of WAV and FLAC output; 32 means float. Defaults to
+    /// 32 for WAV and 24 for FLAC.
+    pub bit_depth: Option<u16>,
+    /// Scale applied to the pan of every timestamp; 0 collapses everything
+    /// to the center.
+    pub pan_width: f32,
+    /// Maximum number of voices of the same sound playing at once.
+    pub max_voices_per_sound: Option<usize>,
+    /// Maximum number of voices playing at once.
+    pub max_voices: Option<usize>,
+    /// Voice cut when a limit is exceeded.
+    pub voice_stealing: VoiceStealing,
+    /// Window in milliseconds within which hits of the same sound at the
+    /// same rate are merged into one voice with summed volume; 0 disables
+    /// merging.
+    pub merge_window: f64,
+    /// Limiting or normalization of the mix. Stems are scaled along with a
+    /// normalized mix but are never limited.
+    pub dynamics: Dynamics,
+    /// Ceiling in dBFS of the limiter and the normalization target.
+    pub ceiling: f32,
+    /// Music mixed under the hitsounds, making the output the final mix.
+    /// It is left out of voice limits and merging, and its stem is
+    /// `<output>-music.wav`.
+    pub music: Option<MusicTrack>,
+    /// Speed of the whole render. Timestamps, the length and the music
+    /// offset are given at normal speed and divided by it, and the music
+    /// is time-stretched with its pitch preserved. Hitsounds keep their
+    /// own speed.
+    pub playback_rate: f64,
+}
+
+impl Default for MixOptions {
+    fn default() -> Self {
+        Self {
+            stems: false,
+            resample_quality: ResampleQuality::default(),
+            sample_rate: SAMPLE_RATE,
+            bit_depth: None,
+            pan_width: 1.0,
+            max_voices_per_sound: None,
+            max_voices: None,
+            voice_stealing: VoiceStealing::default(),
+            merge_window: 0.0,
+            dynamics: Dynamics::default(),
+            ceiling: -1.0,
+            music: None,
+            playback_rate: 1.0,
+        }
+    }
+}
+
+/// Path of the stem for `key` derived from the mix output path.
+fn stem_path(output: &str, key: &str) -> String {
+    let path = std::path::Path::new(output);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let safe_key: String = key
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    path.with_file_name(format!("{}-{}.wav", stem, safe_key))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Throttled progress reporting of a mixing job through the app, the
+/// WebSocket clients and the webhook.
+struct MixProgress<'a> {
+    app: &'a AppHandle,
+    start: Instant,
+    last_report: Instant,
+}
+
+impl<'a> MixProgress<'a> {
+    fn new(app: &'a AppHandle) -> Self {
+        let now = Instant::now();
+        Self {
+            app,
+            start: now,
+            last_report: now,
+        }
+    }
+
+    /// Report `ratio` (0.0 - 1.0) of the job as done, at most once per
+    /// `PROGRESS_INTERVAL`.
+    fn update(&mut self, ratio: f64) {
+        if self.last_report.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+        self.last_report = Instant::now();
+
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let eta_seconds = (ratio > 0.0).then(|| (elapsed / ratio - elapsed).max(0.0));
+        let payload = serde_json::json!({
+            "progress": ratio * 100.0,
+            "eta": eta_seconds,
+        });
+        self.app.emit("audio-mixing-progress", &payload).unwrap();
+        crate::ws_server::broadcast_event("audio-mixing-progress", payload);
+        send_webhook_notification("mixing_audio", ratio, eta_seconds);
+    }
+}
+
+/// Check the voice limits and the timeline of a mix before starting it.
+fn check_mix(
+    sounds: &[Sound],
+    timestamps: &[Timestamp],
+    length: f64,
+    options: &MixOptions,
+) -> Result<(), String> {
+    if options.max_voices == Some(0) || options.max_voices_per_sound == Some(0) {
+        return Err("Voice limits must be at least 1".to_string());
+    }
+    if !SAMPLE_RATES.contains(&options.sample_rate) {
+        return Err(format!(
+            "Sample rate must be between {} and {} Hz, got {}",
+            SAMPLE_RATES.start(),
+            SAMPLE_RATES.end(),
+            options.sample_rate
+        ));
+    }
+    crate::ffmpeg::check_playback_rate(options.playback_rate)?;
+
+    let errors: Vec<String> = validate_timeline(sounds, timestamps, length)
+        .iter()
+        .filter(|problem| problem.is_error())
+        .map(|problem| problem.to_string())
+        .collect();
+    if !errors.is_empty() {
+        return Err(format!("Invalid timeline: {}", errors.join("; ")));
+    }
+    Ok(())
+}
+
+/// Mix `timestamps` into `output` on the calling thread, reporting progress
+/// as it goes.
+fn mix_sync(
+    app: &AppHandle,
+    sounds: &[Sound],
+    timestamps: &[Timestamp],
+    length: f64,
+    output: &str,
+    options: &MixOptions,
+) -> Result<dynamics::MixReport, String> {
+    print!("[TAURI] Mixing audio...");
+
+    // Decoding sounds
+    let mut decoded_sound_map: HashMap<String, Arc<Vec<f32>>> = HashMap::new();
+    let cache_dir = cache::cache_dir(app);
+
+    for sound in sounds {
+        decoded_sound_map.insert(
+            sound.key.clone(),
+            decode_sound(
+                sound,
+                options.sample_rate,
+                options.resample_quality,
+                cache_dir.as_deref(),
+            )?,
+        );
+    }
+
+    // The music is streamed through the mix rather than decoded up front,
+    // as it can be hours long
+    let sample_rate = options.sample_rate as f64;
+    let mut music = match &options.music {
+        Some(track) => Some(music::MusicStream::open(
+            &track.data,
+            track.volume,
+            options.sample_rate,
+            options.resample_quality,
+            options.playback_rate,
+            (track.offset / options.playback_rate * sample_rate).round() as i64,
+        )?),
+        None => None,
+    };
+
+    // Sounds resampled to playback rates other than 1.0, keyed by
+    // sound and the bits of the rate
+    let mut variants: HashMap<(&str, u32), Vec<f32>> = HashMap::new();
+    for timestamp in timestamps {
+        let sound_samples = match decoded_sound_map.get(&timestamp.sound) {
+            Some(samples) => samples,
+            None => {
+                return Err(format!(
+                    "Sound {} not found in decoded sound list",
+                    timestamp.sound
+                ))
+            }
+        };
+        if timestamp.rate != 1.0 {
+            variants
+                .entry((&timestamp.sound, timestamp.rate.to_bits()))
+                .or_insert_with(|| {
+                    resample::resample_stereo(
+                        sound_samples,
+                        timestamp.rate as f64,
+                        options.resample_quality,
+                    )
+                });
+        }
+    }
+
+    let voices: Vec<mixer::Voice> = timestamps
+        .iter()
+        .map(|timestamp| {
+            let samples = if timestamp.rate == 1.0 {
+                decoded_sound_map[&timestamp.sound].as_slice()
+            } else {
+                variants[&(timestamp.sound.as_str(), timestamp.rate.to_bits())].as_slice()
+            };
+            mixer::Voice {
+                key: &timestamp.sound,
+                rate: timestamp.rate,
+                samples,
+                start: (timestamp.time / options.playback_rate * options.sample_rate as f64).round()
+                    as usize,
+                volume: timestamp.volume,
+                pan: (timestamp.pan * options.pan_width).clamp(-1.0, 1.0),
+                release: 0,
+            }
+        })
+        .collect();
+
+    let mut voices = if options.merge_window > 0.0 {
+        polyphony::merge(
+            voices,
+            (options.merge_window / 1000.0 * sample_rate) as usize,
+        )
+    } else {
+        voices
+    };
+    polyphony::limit(
+        &mut voices,
+        options.max_voices_per_sound,
+        options.max_voices,
+        options.voice_stealing,
+        (STEAL_RELEASE * sample_rate) as usize,
+    );
+    let mut mixer = mixer::Mixer::new(
+        voices,
+        (length / options.playback_rate * options.sample_rate as f64) as usize,
+    );
+
+    let mut writer = OutputWriter::create(output, options.sample_rate, options.bit_depth)?;
+    let mut stem_keys = if options.stems {
+        mixer.keys()
+    } else {
+        Vec::new()
+    };
+    // Validation keeps sounds from using the music's key
+    if options.stems && music.is_some() {
+        stem_keys.push(MUSIC_KEY);
+    }
+    let mut stem_writers = HashMap::new();
+    for &key in &stem_keys {
+        let stem_writer = OutputWriter::create(
+            &stem_path(output, key),
+            options.sample_rate,
+            options.bit_depth,
+        )?;
+        stem_writers.insert(key, stem_writer);
+    }
+
+    // Mix a run of blocks in parallel, then stream them to the
+    // outputs in order
+    let mut blocks: Vec<mixer::Block> = (0..PARALLEL_BLOCKS)
+        .map(|_| mixer::Block::new(&stem_keys))
+        .collect();
+    let mut progress = MixProgress::new(app);
+    let passes = if options.dynamics == Dynamics::Normalize {
+        2.0
+    } else {
+        1.0
+    };
+
+    let mut meter = dynamics::Meter::new(options.sample_rate);
+    let mut gain = 1.0;
+    if options.dynamics == Dynamics::Normalize {
+        // Measure the whole mix first, then render it again scaled
+        // to the ceiling
+        loop {
+            let count = mixer.next_blocks(&mut blocks);
+            if count == 0 {
+                break;
+            }
+            if let Some(music) = &mut music {
+                music.mix_into(&mut blocks[..count])?;
+            }
+            for block in &blocks[..count] {
+                meter.add(&block.mix);
+            }
+            progress.update(mixer.progress() / passes);
+        }
+        mixer.rewind();
+        if let Some(music) = &mut music {
+            music.rewind()?;
+        }
+        gain = dynamics::normalization_gain(meter.peak(), options.ceiling);
+    }
+    let mut limiter = (options.dynamics == Dynamics::Limit)
+        .then(|| dynamics::Limiter::new(options.ceiling, options.sample_rate));
+
+    loop {
+        let count = mixer.next_blocks(&mut blocks);
+        if count == 0 {
+            break;
+        }
+        if let Some(music) = &mut music {
+            music.mix_into(&mut blocks[..count])?;
+        }
+        for block in &mut blocks[..count] {
+            if options.dynamics == Dynamics::Normalize {
+                for sample in block
+                    .mix
+                    .iter_mut()
+                    .chain(block.stems.values_mut().flatten())
+                {
+                    *sample *= gain;
+                }
+            } else {
+                meter.add(&block.mix);
+            }
+            if let Some(limiter) = &mut limiter {
+                limiter.process(&mut block.mix);
+            }
+            writer.write(&block.mix)?;
+            for (key, stem) in &block.stems {
+                stem_writers.get_mut(key).unwrap().write(stem)?;
+            }
+        }
+        progress.update((passes - 1.0 + mixer.progress()) / passes);
+    }
+
+    writer.finish()?;
+    for (_, stem_writer) in stem_writers {
+        stem_writer.finish()?;
+    }
+
+    let report = meter.report(
+        options.sample_rate,
+        gain,
+        limiter.map_or(0.0, |limiter| limiter.max_gain_reduction()),
+    );
+    println!(" finished.");
+    Ok(report)
+}
+
+/// `mix_audio` on the calling thread, for jobs building on a mix. Only
+/// progress events are emitted.
+pub fn mix_audio_sync(
+    app: &AppHandle,
+    sounds: &[Sound],
+    timestamps: &[Timestamp],
+    length: f64,
+    output: &str,
+    options: &MixOptions,
+) -> Result<(), String> {
+    check_mix(sounds, timestamps, length, options)?;
+    mix_sync(app, sounds, timestamps, length, output, options).map(|_| ())
+}
+
 pub fn mix_audio(
     app: AppHandle,
     sounds: Vec<Sound>,
     timestamps: Vec<Timestamp>,
     length: f64,
     output: String,
+    options: MixOptions,
 ) -> Result<(), String> {
+    check_mix(&sounds, &timestamps, length, &options)?;
     send_webhook_notification("mixing_audio", 0.0, None);
 
     std::thread::spawn(move || {
-        let mix_result = (|| -> Result<(), String> {
-            print!("[TAURI] Mixing audio...");
-
-            // Decoding sounds
-            let mut decoded_sound_map: HashMap<String, Vec<f32>> = HashMap::new();
-
-            for sound in sounds {
-                let sound_data =
-                    if sound.data.starts_with("data:") || sound.data.contains(";base64,") {
-                        let base64_data = sound.data.split(",").last().unwrap_or(&sound.data);
-                        STANDARD.decode(base64_data).map_err(|e| {
-                            format!("Error decoding base64 sound data for {}: {}", sound.key, e)
-                        })?
-                    } else {
-                        std::fs::read(&sound.data).map_err(|e| {
-                            format!(
-                                "Error reading sound file {} for {}: {}",
-                                sound.data, sound.key, e
-                            )
-                        })?
-                    };
-
-                let cursor = Cursor::new(sound_data);
-                let source = Decoder::new(cursor)
-                    .map_err(|e| format!("Error decoding audio for {}: {}", sound.key, e))?;
-
-                let decoded_samples: Vec<f32> = source
-                    .map(|sample| sample as f32 / i16::MAX as f32) // i16 -> f32
-                    .collect();
-                decoded_sound_map.insert(sound.key.clone(), decoded_samples);
+        match mix_sync(&app, &sounds, &timestamps, length, &output, &options) {
+            Ok(report) => {
+                app.emit("audio-mixing-finished", &report).unwrap();
+                crate::ws_server::broadcast_event(
+                    "audio-mixing-finished",
+                    serde_json::to_value(&report).unwrap(),
+                );
             }
+            Err(e) => {
+                eprintln!("[TAURI] Audio mixing failed: {}", e);
+                app.emit("audio-mixing-failed", &e).unwrap();
+                crate::ws_server::broadcast_event("audio-mixing-failed", serde_json::json!(e));
+            }
+        }
+    });
 
-            let spec = WavSpec {
-                channels: 2,         // Stereo output
-                sample_rate: 48000,  // Standard audio sample rate
-                bits_per_sample: 32, // 32-bit audio
-                sample_format: SampleFormat::Float,
-            };
+    Ok(())
+}
 
-            // Initialize a vector to store combined audio samples
-            let mut combined_samples =
-                vec![0.0f32; (length * spec.sample_rate as f64) as usize * 2];
+/// Optional behaviour of `export_audio`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExportOptions {
+    /// Bitrate of MP3 and Ogg output, as accepted by FFmpeg.
+    pub bitrate: String,
+    /// Options of the underlying mix. Its bit depth applies to FLAC and WAV
+    /// output; stems are never written.
+    pub mix: MixOptions,
+}
 
-            // Process each timestamp to mix audio
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            bitrate: "192k".to_string(),
+            mix: MixOptions::default(),
+        }
+    }
+}
 
-            for timestamp in timestamps {
-                let sound_samples = match decoded_sound_map.get(&timestamp.sound) {
-                    Some(samples) => samples,
-                    None => {
-                        return Err(format!(
-                            "Sound {} not found in decoded sound list",
-                            timestamp.sound
-                        ))
-                    }
-                };
-
-                let position_begin =
-                    (timestamp.time * spec.sample_rate as f64).round() as usize * 2;
-
-                // Handle playback rate
-                if timestamp.rate == 1.0 {
-                    // Normal playback rate, no interpolation needed
-                    for (i, sample) in sound_samples.iter().enumerate() {
-                        let slice = &mut combined_samples[position_begin..];
-                        if i >= slice.len() {
-                            break;
-                        }
-                        slice[i] += sample * timestamp.volume;
-                    }
-                } else {
-                    // Adjusted playback rate using linear interpolation
-                    let sample_count = sound_samples.len();
-                    let mut source_idx: f32 = 0.0;
-
-                    let mut i = 0;
-                    while source_idx < sample_count as f32
-                        && i < combined_samples.len() - position_begin
-                    {
-                        let source_idx_floor = source_idx.floor() as usize;
-                        let source_idx_ceil = source_idx.ceil() as usize;
-
-                        if source_idx_ceil >= sample_count {
-                            break;
-                        }
+/// Mix the chart to a temporary float WAV and encode it to `output` as MP3,
+/// Ogg, FLAC or WAV, tagged with `metadata`.
+#[allow(clippy::too_many_arguments)]
+pub fn export_audio(
+    app: AppHandle,
+    sounds: Vec<Sound>,
+    timestamps: Vec<Timestamp>,
+    length: f64,
+    output: String,
+    metadata: crate::ffmpeg::ChartMetadata,
+    options: ExportOptions,
+) -> Result<(), String> {
+    let codec = crate::ffmpeg::export_codec(&output, &options.bitrate, options.mix.bit_depth)?;
+    let mix_options = MixOptions {
+        stems: false,
+        bit_depth: None,
+        ..options.mix
+    };
+    check_mix(&sounds, &timestamps, length, &mix_options)?;
+    send_webhook_notification("mixing_audio", 0.0, None);
 
-                        let frac = source_idx - source_idx_floor as f32;
-                        let sample = if source_idx_floor == source_idx_ceil {
-                            sound_samples[source_idx_floor]
-                        } else {
-                            // Linear interpolation between adjacent samples
-                            let s1 = sound_samples[source_idx_floor];
-                            let s2 = sound_samples[source_idx_ceil];
-                            s1 * (1.0 - frac) + s2 * frac
-                        };
-
-                        let output_idx = position_begin + i;
-                        if output_idx < combined_samples.len() {
-                            combined_samples[output_idx] += sample * timestamp.volume;
-                        }
+    let hash = blake3::hash(output.as_bytes()).to_hex();
+    let mix_path = std::env::temp_dir()
+        .join(format!("phizone-export-{}.wav", &hash[..16]))
+        .to_string_lossy()
+        .into_owned();
 
-                        i += 1;
-                        source_idx += timestamp.rate;
-                    }
-                }
-            }
+    std::thread::spawn(move || {
+        let result = mix_sync(&app, &sounds, &timestamps, length, &mix_path, &mix_options)
+            .and_then(|report| {
+                crate::ffmpeg::encode_audio_sync(&mix_path, &output, &codec, &metadata)?;
+                Ok(report)
+            });
+        let _ = std::fs::remove_file(&mix_path);
 
-            let mut proc = cmd_hidden("ffmpeg")
-                .args(
-                    format!("-y -f f32le -ar 48000 -ac 2 -i - -c:a pcm_f32le -f wav")
-                        .split_whitespace(),
-                )
-                .arg(output)
-                .stdin(Stdio::piped())
-                .stderr(Stdio::inherit())
-                .spawn()
-                .map_err(|e| e.to_string())?;
-            let input = proc.stdin.as_mut().unwrap();
-            let mut writer = BufWriter::new(input);
-            for sample in combined_samples.into_iter() {
-                writer
-                    .write_all(&sample.to_le_bytes())
-                    .map_err(|e| e.to_string())?;
+        match result {
+            Ok(report) => {
+                app.emit("audio-export-finished", &report).unwrap();
+                crate::ws_server::broadcast_event(
+                    "audio-export-finished",
+                    serde_json::to_value(&report).unwrap(),
+                );
             }
-            drop(writer);
-            if proc.wait().map_err(|e| e.to_string())?.success() {
-                app.emit("audio-mixing-finished", ()).unwrap();
-                crate::ws_server::broadcast_event("audio-mixing-finished", serde_json::Value::Null);
-                println!(" finished.");
-                Ok(())
-            } else {
-                Err("FFmpeg process failed".to_string())
+            Err(e) => {
+                eprintln!("[TAURI] Audio export failed: {}", e);
+                app.emit("audio-export-failed", &e).unwrap();
+                crate::ws_server::broadcast_event("audio-export-failed", serde_json::json!(e));
             }
-        })();
-
-        if let Err(e) = mix_result {
-            eprintln!("[TAURI] Audio mixing failed: {}", e);
         }
     });
 
     Ok(())
 }
+
+/// Waveform of the audio at `path` (a file path or `blob:<id>`) with
+/// `buckets` buckets between `start` and `end` seconds. The file is
+/// summarized once and cached alongside the decoded sounds by its content
+/// hash.
+pub fn get_waveform(
+    app: &AppHandle,
+    path: &str,
+    buckets: usize,
+    start: Option<f64>,
+    end: Option<f64>,
+) -> Result<Waveform, String> {
+    let data = load_sound_data(path, path)?;
+    let cache_dir = cache::cache_dir(app);
+    let key = format!("{}-waveform", blake3::hash(&data).to_hex());
+    let summary = match cache::get(cache_dir.as_deref(), &key) {
+        Some(summary) => summary,
+        None => {
+            let decoded = decode::decode(path, data)?;
+            let summary =
+                waveform::summarize(&decoded.samples, decoded.channels, decoded.sample_rate);
+            cache::insert(cache_dir.as_deref(), &key, summary)
+        }
+    };
+    waveform::waveform(&summary, buckets, start, end)
+}
+
+/// Detect onsets and the tempo of the music at `path` (a file path or
+/// `blob:<id>`) and suggest a correction of the chart's offset from its
+/// `note_times`.
+pub fn analyze_offset(
+    path: &str,
+    note_times: &[f64],
+    options: &OffsetOptions,
+) -> Result<OffsetAnalysis, String> {
+    let decoded = decode::decode(path, load_sound_data(path, path)?)?;
+    onset::analyze(
+        &decoded.samples,
+        decoded.channels,
+        decoded.sample_rate,
+        note_times,
+        options,
+    )
+}
+
+/// Decode `sounds` for preview playback through the decoded-sound cache
+/// and start the output. Returns `"device"` or `"null"` for the output in
+/// use.
+pub fn preload(app: &AppHandle, sounds: Vec<Sound>) -> Result<&'static str, String> {
+    let cache_dir = cache::cache_dir(app);
+    let decoded = sounds
+        .into_iter()
+        .map(|sound| {
+            let samples = decode_sound(
+                &sound,
+                SAMPLE_RATE,
+                ResampleQuality::default(),
+                cache_dir.as_deref(),
+            )?;
+            Ok((sound.key, samples))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok(playback::preload(decoded))
+}
+
+/// Size and location of the decoded-sound cache.
+pub fn audio_cache_info(app: &AppHandle) -> CacheInfo {
+    cache::info(cache::cache_dir(app).as_deref())
+}
+
+/// Empty the decoded-sound cache in memory and on disk.
+pub fn clear_audio_cache(app: &AppHandle) -> Result<(), String> {
+    cache::clear(cache::cache_dir(app).as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 16-bit WAV file of interleaved `samples`.
+    fn wav(channels: u16, sample_rate: u32, samples: &[f32]) -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut bytes = std::io::Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut bytes, spec).unwrap();
+        for &sample in samples {
+            writer
+                .write_sample((sample * i16::MAX as f32) as i16)
+                .unwrap();
+        }
+        writer.finalize().unwrap();
+        bytes.into_inner()
+    }
+
+    fn sine(frequency: f32, sample_rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| {
+                0.5 * (2.0 * std::f32::consts::PI * frequency * i as f32 / sample_rate as f32).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mono_is_copied_to_both_sides() {
+        let mono = sine(440.0, 48000, 1000);
+        let stereo = decode_data(
+            "mono",
+            wav(1, 48000, &mono),
+            48000,
+            ResampleQuality::default(),
+        )
+        .unwrap();
+        assert_eq!(stereo.len(), mono.len() * 2);
+        for (frame, &sample) in stereo.chunks_exact(2).zip(&mono) {
+            assert_eq!(frame[0], frame[1]);
+            assert!((frame[0] - sample).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn resampled_sine_keeps_frequency_and_amplitude() {
+        let stereo = decode_data(
+            "sine",
+            wav(1, 44100, &sine(440.0, 44100, 44100)),
+            48000,
+            ResampleQuality::default(),
+        )
+        .unwrap();
+        let frames = stereo.len() / 2;
+        assert!(frames.abs_diff(48000) <= 1);
+
+        // Skip the edges, where the resampler filter runs into silence
+        let left: Vec<f32> = stereo.iter().step_by(2).copied().collect();
+        let middle = &left[4800..43200];
+        let crossings = middle
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count();
+        let frequency = crossings as f64 / (middle.len() as f64 / 48000.0);
+        assert!((frequency - 440.0).abs() < 5.0, "{} Hz", frequency);
+        let peak = middle.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!((peak - 0.5).abs() < 0.01, "peak {}", peak);
+    }
+
+    #[test]
+    fn mix_sample_rate_is_checked() {
+        for sample_rate in [0, 4000, 384000] {
+            let options = MixOptions {
+                sample_rate,
+                ..MixOptions::default()
+            };
+            assert!(check_mix(&[], &[], 1.0, &options).is_err());
+        }
+        assert!(check_mix(&[], &[], 1.0, &MixOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn surround_folds_down() {
+        let attenuation = std::f32::consts::FRAC_1_SQRT_2;
+        // Front left/right, center, LFE, surround left/right
+        let stereo = to_stereo(&[0.1, 0.2, 0.3, 0.9, 0.4, 0.5], 6);
+        assert_eq!(stereo.len(), 2);
+        assert!((stereo[0] - (0.1 + (0.3 + 0.4) * attenuation)).abs() < 1e-6);
+        assert!((stereo[1] - (0.2 + (0.3 + 0.5) * attenuation)).abs() < 1e-6);
+    }
+}
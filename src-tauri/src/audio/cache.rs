@@ -0,0 +1,239 @@
+//! Cache of decoded sounds shared across `mix_audio` calls, kept in memory
+//! and on disk under the app data dir. Entries are keyed by a hash of the
+//! encoded data together with the decoding parameters. Waveform summaries
+//! are kept here as well, keyed by the hash of the file.
+//!
+//! The memory side holds at most `MEMORY_LIMIT` bytes, evicting the least
+//! recently used entries beyond it. Evicted entries are read back from disk
+//! when needed again.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+
+use tauri::{AppHandle, Manager};
+
+use super::ResampleQuality;
+
+/// Bytes of samples kept in memory.
+const MEMORY_LIMIT: u64 = 512 << 20;
+
+static MEMORY_CACHE: LazyLock<Mutex<MemoryCache>> =
+    LazyLock::new(|| Mutex::new(MemoryCache::new(MEMORY_LIMIT)));
+
+/// In-memory entries with least-recently-used eviction.
+struct MemoryCache {
+    entries: HashMap<String, MemoryEntry>,
+    bytes: u64,
+    limit: u64,
+    /// Incremented on every access, ordering the entries by last use.
+    clock: u64,
+}
+
+struct MemoryEntry {
+    samples: Arc<Vec<f32>>,
+    last_used: u64,
+}
+
+fn size_of(samples: &[f32]) -> u64 {
+    (samples.len() * 4) as u64
+}
+
+impl MemoryCache {
+    fn new(limit: u64) -> Self {
+        Self {
+            entries: HashMap::new(),
+            bytes: 0,
+            limit,
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<Arc<Vec<f32>>> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = self.clock;
+        Some(entry.samples.clone())
+    }
+
+    /// Add an entry, then evict the least recently used ones until the
+    /// cache fits its limit again. An entry larger than the limit is not
+    /// kept at all.
+    fn insert(&mut self, key: &str, samples: Arc<Vec<f32>>) {
+        self.clock += 1;
+        self.bytes += size_of(&samples);
+        let entry = MemoryEntry {
+            samples,
+            last_used: self.clock,
+        };
+        if let Some(old) = self.entries.insert(key.to_string(), entry) {
+            self.bytes -= size_of(&old.samples);
+        }
+
+        while self.bytes > self.limit {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            let entry = self.entries.remove(&oldest).unwrap();
+            self.bytes -= size_of(&entry.samples);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+}
+
+/// Extension of cache files, holding little-endian `f32` samples.
+const EXTENSION: &str = "pcm";
+
+/// Directory of the on-disk cache, if the app data dir is available.
+pub fn cache_dir(app: &AppHandle) -> Option<PathBuf> {
+    app.path()
+        .app_data_dir()
+        .ok()
+        .map(|dir| dir.join("audio-cache"))
+}
+
+/// Cache key of `data` decoded to `sample_rate` with `quality`.
+pub fn key(data: &[u8], sample_rate: u32, quality: ResampleQuality) -> String {
+    format!(
+        "{}-{}-{:?}",
+        blake3::hash(data).to_hex(),
+        sample_rate,
+        quality
+    )
+    .to_lowercase()
+}
+
+/// Look up `key` in memory, then on disk.
+pub fn get(dir: Option<&Path>, key: &str) -> Option<Arc<Vec<f32>>> {
+    if let Some(samples) = MEMORY_CACHE.lock().unwrap().get(key) {
+        return Some(samples);
+    }
+
+    let bytes = std::fs::read(dir?.join(key).with_extension(EXTENSION)).ok()?;
+    let samples: Arc<Vec<f32>> = Arc::new(
+        bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect(),
+    );
+    MEMORY_CACHE.lock().unwrap().insert(key, samples.clone());
+    Some(samples)
+}
+
+/// Store `samples` under `key` in memory and, if `dir` is given, on disk.
+/// Failing to write the file only loses the disk entry.
+pub fn insert(dir: Option<&Path>, key: &str, samples: Vec<f32>) -> Arc<Vec<f32>> {
+    let samples = Arc::new(samples);
+    MEMORY_CACHE.lock().unwrap().insert(key, samples.clone());
+
+    if let Some(dir) = dir {
+        if let Err(e) = write_entry(dir, key, &samples) {
+            eprintln!("[TAURI] Failed to write audio cache entry {}: {}", key, e);
+        }
+    }
+    samples
+}
+
+/// Write an entry through a temporary file, so that a concurrent reader
+/// never sees a partial one.
+fn write_entry(dir: &Path, key: &str, samples: &[f32]) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let bytes: Vec<u8> = samples
+        .iter()
+        .flat_map(|sample| sample.to_le_bytes())
+        .collect();
+    let temp = dir.join(key).with_extension("tmp");
+    std::fs::write(&temp, bytes)?;
+    std::fs::rename(&temp, dir.join(key).with_extension(EXTENSION))
+}
+
+/// Size of the cache, returned by `get_audio_cache_info`.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheInfo {
+    pub path: Option<String>,
+    pub memory_entries: usize,
+    pub memory_bytes: u64,
+    /// Bytes kept in memory before the least recently used entries are
+    /// evicted.
+    pub memory_limit: u64,
+    pub disk_entries: usize,
+    pub disk_bytes: u64,
+}
+
+fn disk_entries(dir: &Path) -> Vec<(PathBuf, u64)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == EXTENSION))
+        .map(|entry| {
+            let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+            (entry.path(), size)
+        })
+        .collect()
+}
+
+pub fn info(dir: Option<&Path>) -> CacheInfo {
+    let (memory_entries, memory_bytes, memory_limit) = {
+        let cache = MEMORY_CACHE.lock().unwrap();
+        (cache.entries.len(), cache.bytes, cache.limit)
+    };
+    let disk = dir.map(disk_entries).unwrap_or_default();
+    CacheInfo {
+        path: dir.map(|dir| dir.to_string_lossy().into_owned()),
+        memory_entries,
+        memory_bytes,
+        memory_limit,
+        disk_entries: disk.len(),
+        disk_bytes: disk.iter().map(|(_, size)| size).sum(),
+    }
+}
+
+/// Drop all entries from memory and disk.
+pub fn clear(dir: Option<&Path>) -> Result<(), String> {
+    MEMORY_CACHE.lock().unwrap().clear();
+    for (path, _) in dir.map(disk_entries).unwrap_or_default() {
+        std::fs::remove_file(&path)
+            .map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_cache_evicts_least_recently_used() {
+        // Room for three entries of four samples
+        let mut cache = MemoryCache::new(48);
+        for key in ["a", "b", "c"] {
+            cache.insert(key, Arc::new(vec![0.0; 4]));
+        }
+        assert!(cache.get("a").is_some());
+        cache.insert("d", Arc::new(vec![0.0; 4]));
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some() && cache.get("c").is_some());
+        assert_eq!(cache.bytes, 48);
+
+        // Replacing an entry accounts for the old one
+        cache.insert("a", Arc::new(vec![0.0; 8]));
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.bytes, 48);
+
+        cache.insert("huge", Arc::new(vec![0.0; 16]));
+        assert!(cache.entries.is_empty());
+        assert_eq!(cache.bytes, 0);
+    }
+}
@@ -0,0 +1,329 @@
+//! Decoding of encoded sounds to interleaved `f32` samples.
+//!
+//! WAV, AIFF, FLAC, ALAC, Vorbis, MP1/2/3 and AAC (ADTS, MP4, CAF, Matroska)
+//! are decoded in pure Rust by symphonia. Opus is the exception: symphonia
+//! has no Opus decoder and no pure-Rust one exists yet, so Ogg and Matroska
+//! Opus is streamed through FFmpeg, which is then required. Without it,
+//! Opus sounds are reported as unsupported like any other format.
+
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::process::{Child, ChildStdout, Stdio};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL, CODEC_TYPE_OPUS};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+/// Sample rate of sounds decoded through FFmpeg.
+const FFMPEG_SAMPLE_RATE: u32 = 48000;
+
+pub struct Decoded {
+    /// Interleaved samples.
+    pub samples: Vec<f32>,
+    pub channels: usize,
+    pub sample_rate: u32,
+}
+
+/// Name of the container of `data`, recognized from its magic bytes.
+pub fn detect_container(data: &[u8]) -> &'static str {
+    match data {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => "WAV",
+        [b'F', b'O', b'R', b'M', _, _, _, _, b'A', b'I', b'F', ..] => "AIFF",
+        [b'f', b'L', b'a', b'C', ..] => "FLAC",
+        [b'O', b'g', b'g', b'S', ..] => "Ogg",
+        [b'c', b'a', b'f', b'f', ..] => "CAF",
+        [0x1A, 0x45, 0xDF, 0xA3, ..] => "Matroska",
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => "MP4",
+        [b'I', b'D', b'3', ..] => "MP3",
+        [0xFF, second, ..] if second & 0xF6 == 0xF0 => "AAC (ADTS)",
+        [0xFF, second, ..] if second & 0xE0 == 0xE0 => "MPEG audio",
+        _ => "unknown",
+    }
+}
+
+/// Decoder of a sound yielding its samples packet by packet.
+pub struct StreamDecoder {
+    key: String,
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    channels: usize,
+    buffer: Option<SampleBuffer<f32>>,
+}
+
+/// Interleaved samples of one decoded packet.
+pub struct Packet<'a> {
+    pub samples: &'a [f32],
+    pub channels: usize,
+    pub sample_rate: u32,
+}
+
+/// A sound opened for decoding.
+pub enum Opened {
+    Native(StreamDecoder),
+    /// Opus in the named container, which has to go through FFmpeg.
+    Opus(&'static str),
+}
+
+/// Open the sound `key` from its encoded data in `source`.
+pub fn open(key: &str, mut source: Box<dyn MediaSource>) -> Result<Opened, String> {
+    let mut magic = Vec::new();
+    (&mut source)
+        .take(16)
+        .read_to_end(&mut magic)
+        .and_then(|_| source.seek(SeekFrom::Start(0)))
+        .map_err(|e| format!("Error reading sound data for {}: {}", key, e))?;
+    let container = detect_container(&magic);
+    let unsupported = || format!("Unsupported format for {}: {}", key, container);
+
+    let source = MediaSourceStream::new(source, Default::default());
+    let probed = symphonia::default::get_probe()
+        .format(
+            &Hint::new(),
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|e| match e {
+            Error::Unsupported(_) => unsupported(),
+            e => format!("Error reading {} data for {}: {}", container, key, e),
+        })?;
+    let format = probed.format;
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| format!("No audio track in {} data for {}", container, key))?;
+    if track.codec_params.codec == CODEC_TYPE_OPUS {
+        return Ok(Opened::Opus(container));
+    }
+    let track_id = track.id;
+    let decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|e| match e {
+            Error::Unsupported(_) => unsupported(),
+            e => format!("Error decoding audio for {}: {}", key, e),
+        })?;
+
+    Ok(Opened::Native(StreamDecoder {
+        key: key.to_string(),
+        format,
+        decoder,
+        track_id,
+        channels: 0,
+        buffer: None,
+    }))
+}
+
+impl StreamDecoder {
+    /// The next packet, or `None` at the end of the sound.
+    pub fn next_packet(&mut self) -> Result<Option<Packet<'_>>, String> {
+        let key = &self.key;
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(format!("Error decoding audio for {}: {}", key, e)),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // Corrupt packets are skipped, as players do
+                Err(Error::DecodeError(_)) => continue,
+                Err(e) => return Err(format!("Error decoding audio for {}: {}", key, e)),
+            };
+            let spec = *decoded.spec();
+            let needed = decoded.capacity() * spec.channels.count();
+            if spec.channels.count() != self.channels
+                || self.buffer.as_ref().is_none_or(|b| b.capacity() < needed)
+            {
+                self.buffer = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+            }
+            self.channels = spec.channels.count();
+            let buffer = self.buffer.as_mut().unwrap();
+            buffer.copy_interleaved_ref(decoded);
+            return Ok(Some(Packet {
+                samples: buffer.samples(),
+                channels: self.channels,
+                sample_rate: spec.rate,
+            }));
+        }
+    }
+}
+
+/// Bytes read from FFmpeg at a time.
+const PIPE_BYTES: usize = 1 << 16;
+
+/// Decoder of a sound symphonia cannot decode, piping it through FFmpeg and
+/// yielding stereo at `FFMPEG_SAMPLE_RATE` as it comes out.
+pub struct FfmpegDecoder {
+    key: String,
+    process: Child,
+    stdout: ChildStdout,
+    feeder: Option<JoinHandle<std::io::Result<u64>>>,
+    stderr: Option<JoinHandle<String>>,
+    /// Bytes read from FFmpeg, of which the first `partial` are the start
+    /// of a frame not complete yet.
+    bytes: Vec<u8>,
+    partial: usize,
+    samples: Vec<f32>,
+}
+
+impl FfmpegDecoder {
+    /// Start decoding the sound `key` in `format` read from `input`. Without
+    /// FFmpeg, the format is reported as unsupported.
+    pub fn spawn(
+        key: &str,
+        mut input: impl Read + Send + 'static,
+        format: &str,
+    ) -> Result<Self, String> {
+        let mut process = crate::ffmpeg::command()
+            .args(
+                format!("-v error -i - -f f32le -ac 2 -ar {} -", FFMPEG_SAMPLE_RATE)
+                    .split_whitespace(),
+            )
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => {
+                    format!("Unsupported format for {}: {}", key, format)
+                }
+                _ => format!("Error starting FFmpeg to decode {}: {}", key, e),
+            })?;
+
+        // Feed stdin and drain stderr from other threads so that neither
+        // pipe can block FFmpeg while stdout is read
+        let mut stdin = process.stdin.take().unwrap();
+        let feeder = std::thread::spawn(move || std::io::copy(&mut input, &mut stdin));
+        let mut stderr = process.stderr.take().unwrap();
+        let stderr = std::thread::spawn(move || {
+            let mut output = String::new();
+            let _ = stderr.read_to_string(&mut output);
+            output
+        });
+
+        Ok(Self {
+            key: key.to_string(),
+            stdout: process.stdout.take().unwrap(),
+            process,
+            feeder: Some(feeder),
+            stderr: Some(stderr),
+            bytes: Vec::new(),
+            partial: 0,
+            samples: Vec::new(),
+        })
+    }
+
+    /// The next samples, or `None` at the end of the sound.
+    pub fn next_packet(&mut self) -> Result<Option<Packet<'_>>, String> {
+        const FRAME_BYTES: usize = 2 * std::mem::size_of::<f32>();
+        loop {
+            self.bytes.resize(self.partial + PIPE_BYTES, 0);
+            let read = self
+                .stdout
+                .read(&mut self.bytes[self.partial..])
+                .map_err(|e| format!("Error reading FFmpeg output for {}: {}", self.key, e))?;
+            if read == 0 {
+                self.finish()?;
+                return Ok(None);
+            }
+
+            let total = self.partial + read;
+            let whole = total / FRAME_BYTES * FRAME_BYTES;
+            self.samples.clear();
+            self.samples.extend(
+                self.bytes[..whole]
+                    .chunks_exact(4)
+                    .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap())),
+            );
+            self.bytes.copy_within(whole..total, 0);
+            self.partial = total - whole;
+            if whole > 0 {
+                return Ok(Some(Packet {
+                    samples: &self.samples,
+                    channels: 2,
+                    sample_rate: FFMPEG_SAMPLE_RATE,
+                }));
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        let status = self.process.wait().map_err(|e| e.to_string())?;
+        // FFmpeg may stop reading once it has what it needs, so a broken pipe
+        // here is not an error in itself
+        if let Some(feeder) = self.feeder.take() {
+            let _ = feeder.join();
+        }
+        let stderr = self
+            .stderr
+            .take()
+            .and_then(|stderr| stderr.join().ok())
+            .unwrap_or_default();
+        if !status.success() {
+            return Err(format!(
+                "Error decoding audio for {} with FFmpeg: {}",
+                self.key,
+                stderr.trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FfmpegDecoder {
+    /// Stop FFmpeg when the sound is dropped before its end.
+    fn drop(&mut self) {
+        if self.feeder.is_some() {
+            let _ = self.process.kill();
+            let _ = self.process.wait();
+        }
+    }
+}
+
+/// Decode the sound `key` from its encoded `data`.
+pub fn decode(key: &str, data: Vec<u8>) -> Result<Decoded, String> {
+    let data: Arc<[u8]> = data.into();
+    let mut samples = Vec::new();
+    let mut channels = 0;
+    let mut sample_rate = 0;
+    let mut append = |packet: Packet| {
+        samples.extend_from_slice(packet.samples);
+        channels = packet.channels;
+        sample_rate = packet.sample_rate;
+    };
+
+    match open(key, Box::new(Cursor::new(data.clone())))? {
+        Opened::Native(mut decoder) => {
+            while let Some(packet) = decoder.next_packet()? {
+                append(packet);
+            }
+        }
+        Opened::Opus(container) => {
+            let format = format!("{}/Opus", container);
+            let mut decoder = FfmpegDecoder::spawn(key, Cursor::new(data), &format)?;
+            while let Some(packet) = decoder.next_packet()? {
+                append(packet);
+            }
+        }
+    }
+
+    Ok(Decoded {
+        samples,
+        channels,
+        sample_rate,
+    })
+}
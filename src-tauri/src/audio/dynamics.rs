@@ -0,0 +1,222 @@
+//! Level control of the final mix: an optional soft-knee limiter or peak
+//! normalization, and metering of the raw mix for the clipping report.
+
+/// Width of the limiter knee in dB, centered on the ceiling.
+const KNEE_DB: f32 = 6.0;
+
+/// Time in seconds for the limiter gain to recover by a factor of e.
+const RELEASE: f32 = 0.05;
+
+/// Length in seconds of the windows clipping is counted in.
+const RANGE_SECONDS: f64 = 0.5;
+
+/// Number of clipping ranges listed in the report.
+const WORST_RANGES: usize = 10;
+
+/// Processing applied to the mix before it is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dynamics {
+    /// Write the raw sum, clipping at full scale in integer formats.
+    #[default]
+    Off,
+    /// Soft-knee peak limiter keeping the output below the ceiling.
+    Limit,
+    /// Scale the whole mix so that its peak lands on the ceiling.
+    Normalize,
+}
+
+/// Levels of the raw mix, sent with `audio-mixing-finished`.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MixReport {
+    /// Sample peak in dBFS, or `None` if the mix is silent.
+    pub peak: Option<f64>,
+    /// Samples beyond full scale.
+    pub clipped_samples: u64,
+    /// Time ranges with the most clipped samples, worst first.
+    pub worst_ranges: Vec<ClipRange>,
+    /// Normalization gain in dB.
+    pub gain: f64,
+    /// Largest gain reduction of the limiter in dB.
+    pub max_gain_reduction: f64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClipRange {
+    /// Start in seconds.
+    pub start: f64,
+    /// End in seconds.
+    pub end: f64,
+    /// Sample peak in dBFS.
+    pub peak: f64,
+    pub clipped_samples: u64,
+}
+
+fn to_db(level: f32) -> f64 {
+    20.0 * (level as f64).log10()
+}
+
+fn from_db(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Gain scaling a mix peaking at `peak` to `ceiling` dBFS.
+pub fn normalization_gain(peak: f32, ceiling: f32) -> f32 {
+    if peak > 0.0 {
+        from_db(ceiling) / peak
+    } else {
+        1.0
+    }
+}
+
+/// Measures peak and clipping of interleaved stereo blocks in order.
+pub struct Meter {
+    window_frames: usize,
+    /// Peak and clipped sample count per window.
+    windows: Vec<(f32, u64)>,
+    frames: usize,
+}
+
+impl Meter {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            window_frames: ((RANGE_SECONDS * sample_rate as f64) as usize).max(1),
+            windows: Vec::new(),
+            frames: 0,
+        }
+    }
+
+    pub fn add(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(2) {
+            let window = self.frames / self.window_frames;
+            if window == self.windows.len() {
+                self.windows.push((0.0, 0));
+            }
+            let (peak, clipped) = &mut self.windows[window];
+            for sample in frame {
+                let level = sample.abs();
+                *peak = peak.max(level);
+                if level > 1.0 {
+                    *clipped += 1;
+                }
+            }
+            self.frames += 1;
+        }
+    }
+
+    pub fn peak(&self) -> f32 {
+        self.windows
+            .iter()
+            .fold(0.0, |peak, window| peak.max(window.0))
+    }
+
+    /// Build the report, merging adjacent clipping windows into ranges.
+    pub fn report(&self, sample_rate: u32, gain: f32, max_gain_reduction: f32) -> MixReport {
+        let window_seconds = self.window_frames as f64 / sample_rate as f64;
+        let mut ranges: Vec<ClipRange> = Vec::new();
+        let mut previous_clipped = false;
+        for (i, &(peak, clipped)) in self.windows.iter().enumerate() {
+            if clipped > 0 {
+                let end =
+                    ((i + 1) as f64 * window_seconds).min(self.frames as f64 / sample_rate as f64);
+                match ranges.last_mut() {
+                    Some(range) if previous_clipped => {
+                        range.end = end;
+                        range.peak = range.peak.max(to_db(peak));
+                        range.clipped_samples += clipped;
+                    }
+                    _ => ranges.push(ClipRange {
+                        start: i as f64 * window_seconds,
+                        end,
+                        peak: to_db(peak),
+                        clipped_samples: clipped,
+                    }),
+                }
+            }
+            previous_clipped = clipped > 0;
+        }
+        ranges.sort_by_key(|range| std::cmp::Reverse(range.clipped_samples));
+        ranges.truncate(WORST_RANGES);
+
+        let peak = self.peak();
+        MixReport {
+            peak: (peak > 0.0).then(|| to_db(peak)),
+            clipped_samples: self.windows.iter().map(|window| window.1).sum(),
+            worst_ranges: ranges,
+            gain: to_db(gain),
+            max_gain_reduction: max_gain_reduction as f64,
+        }
+    }
+}
+
+/// Stereo-linked peak limiter with a soft knee. The attack is instant, so
+/// the output never exceeds the ceiling.
+pub struct Limiter {
+    ceiling: f32,
+    release_coefficient: f32,
+    /// Current gain in dB, at most 0.
+    gain: f32,
+    /// Largest gain reduction so far in dB.
+    max_gain_reduction: f32,
+}
+
+impl Limiter {
+    /// Limiter for a `ceiling` in dBFS.
+    pub fn new(ceiling: f32, sample_rate: u32) -> Self {
+        Self {
+            ceiling,
+            release_coefficient: (-1.0 / (RELEASE * sample_rate as f32)).exp(),
+            gain: 0.0,
+            max_gain_reduction: 0.0,
+        }
+    }
+
+    /// Static gain in dB for a frame peaking at `level` dBFS.
+    fn target_gain(&self, level: f32) -> f32 {
+        let over = level - self.ceiling;
+        if over <= -KNEE_DB / 2.0 {
+            0.0
+        } else if over < KNEE_DB / 2.0 {
+            -(over + KNEE_DB / 2.0).powi(2) / (2.0 * KNEE_DB)
+        } else {
+            -over
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let knee_start = from_db(self.ceiling - KNEE_DB / 2.0);
+        for frame in samples.chunks_exact_mut(2) {
+            let level = frame[0].abs().max(frame[1].abs());
+            if level <= knee_start && self.gain == 0.0 {
+                continue;
+            }
+
+            let target = if level > knee_start {
+                self.target_gain(20.0 * level.log10())
+            } else {
+                0.0
+            };
+            self.gain = if target < self.gain {
+                target
+            } else {
+                target + (self.gain - target) * self.release_coefficient
+            };
+            if self.gain > -1e-4 {
+                self.gain = 0.0;
+            } else {
+                self.max_gain_reduction = self.max_gain_reduction.max(-self.gain);
+            }
+
+            let gain = from_db(self.gain);
+            frame[0] *= gain;
+            frame[1] *= gain;
+        }
+    }
+
+    /// Largest gain reduction applied so far in dB.
+    pub fn max_gain_reduction(&self) -> f32 {
+        self.max_gain_reduction
+    }
+}
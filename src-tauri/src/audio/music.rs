@@ -0,0 +1,304 @@
+//! Streaming of the music track of `mix_audio`. The music is decoded,
+//! converted to stereo, resampled and time-stretched as the mix advances,
+//! so that memory use stays flat however long the song is. Opus is streamed
+//! out of FFmpeg the same way.
+
+use std::fs::File;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use symphonia::core::io::MediaSource;
+
+use super::decode::{self, FfmpegDecoder, Opened, StreamDecoder};
+use super::mixer::Block;
+use super::resample::{ResampleQuality, Resampler};
+use super::stretch::Stretcher;
+use super::MUSIC_KEY;
+
+enum Source {
+    Native(StreamDecoder),
+    Ffmpeg(FfmpegDecoder),
+}
+
+/// The music at the output rate and playback rate, placed on the mix
+/// timeline.
+pub struct MusicStream {
+    data: String,
+    volume: f32,
+    sample_rate: u32,
+    quality: ResampleQuality,
+    rate: f64,
+    offset: i64,
+    source: Source,
+    /// Created once the sample rate of the music is known.
+    resampler: Option<Resampler>,
+    stretcher: Stretcher,
+    /// Samples out of the stretcher, read up to index `read`.
+    pending: Vec<f32>,
+    read: usize,
+    finished: bool,
+    /// Frames of silence still to play before the music.
+    delay: usize,
+    /// Frames of music still to skip.
+    skip: usize,
+    /// Buffers between the stages, kept across chunks.
+    resampled: Vec<f32>,
+    block: Vec<f32>,
+}
+
+/// Open the encoded music given as for `Sound::data`. Files and blobs are
+/// read as needed; base64 data is decoded up front.
+fn open_source(data: &str) -> Result<Box<dyn MediaSource>, String> {
+    let path = match data.strip_prefix(crate::blob::BLOB_PREFIX) {
+        Some(id) => crate::blob::path(id)?,
+        None if data.starts_with("data:") || data.contains(";base64,") => {
+            let bytes = super::load_sound_data(MUSIC_KEY, data)?;
+            return Ok(Box::new(Cursor::new(bytes)));
+        }
+        None => PathBuf::from(data),
+    };
+    let file = File::open(&path).map_err(|e| {
+        format!(
+            "Error reading sound file {} for {}: {}",
+            path.display(),
+            MUSIC_KEY,
+            e
+        )
+    })?;
+    Ok(Box::new(file))
+}
+
+impl MusicStream {
+    /// Open the music in `data` for a mix at `sample_rate` and playback
+    /// `rate`, starting `offset` output frames into the mix. A negative
+    /// offset trims the beginning of the music instead.
+    pub fn open(
+        data: &str,
+        volume: f32,
+        sample_rate: u32,
+        quality: ResampleQuality,
+        rate: f64,
+        offset: i64,
+    ) -> Result<Self, String> {
+        let source = match decode::open(MUSIC_KEY, open_source(data)?)? {
+            Opened::Native(decoder) => Source::Native(decoder),
+            Opened::Opus(container) => Source::Ffmpeg(FfmpegDecoder::spawn(
+                MUSIC_KEY,
+                open_source(data)?,
+                &format!("{}/Opus", container),
+            )?),
+        };
+
+        Ok(Self {
+            data: data.to_string(),
+            volume,
+            sample_rate,
+            quality,
+            rate,
+            offset,
+            source,
+            resampler: None,
+            stretcher: Stretcher::new(rate, sample_rate),
+            pending: Vec::new(),
+            read: 0,
+            finished: false,
+            delay: offset.max(0) as usize,
+            skip: (-offset).max(0) as usize,
+            resampled: Vec::new(),
+            block: Vec::new(),
+        })
+    }
+
+    /// Start over from the beginning of the timeline.
+    pub fn rewind(&mut self) -> Result<(), String> {
+        *self = Self::open(
+            &self.data,
+            self.volume,
+            self.sample_rate,
+            self.quality,
+            self.rate,
+            self.offset,
+        )?;
+        Ok(())
+    }
+
+    /// Pass the next chunk of the music through the stages, appending what
+    /// comes out to `pending`.
+    fn pull(&mut self) -> Result<(), String> {
+        let chunk = match &mut self.source {
+            Source::Native(decoder) => decoder.next_packet()?,
+            Source::Ffmpeg(decoder) => decoder.next_packet()?,
+        };
+
+        self.resampled.clear();
+        match chunk {
+            Some(packet) => {
+                let resampler = self.resampler.get_or_insert_with(|| {
+                    Resampler::new(
+                        packet.sample_rate as f64 / self.sample_rate as f64,
+                        self.quality,
+                    )
+                });
+                let stereo = super::to_stereo(packet.samples, packet.channels);
+                resampler.process(&stereo, &mut self.resampled);
+                self.stretcher.process(&self.resampled, &mut self.pending);
+            }
+            None => {
+                if let Some(resampler) = &mut self.resampler {
+                    resampler.finish(&mut self.resampled);
+                    self.stretcher.process(&self.resampled, &mut self.pending);
+                }
+                self.stretcher.finish(&mut self.pending);
+                self.finished = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill `output` with the next interleaved stereo samples of the
+    /// timeline, silent before and after the music.
+    fn read(&mut self, output: &mut [f32]) -> Result<(), String> {
+        let silence = (self.delay * 2).min(output.len());
+        output[..silence].fill(0.0);
+        self.delay -= silence / 2;
+
+        let mut filled = silence;
+        while filled < output.len() {
+            if self.read == self.pending.len() {
+                self.pending.clear();
+                self.read = 0;
+                if self.finished {
+                    output[filled..].fill(0.0);
+                    break;
+                }
+                self.pull()?;
+                continue;
+            }
+
+            let available = self.pending.len() - self.read;
+            if self.skip > 0 {
+                let skipped = (self.skip * 2).min(available);
+                self.read += skipped;
+                self.skip -= skipped / 2;
+                continue;
+            }
+            let count = available.min(output.len() - filled);
+            output[filled..filled + count]
+                .copy_from_slice(&self.pending[self.read..self.read + count]);
+            self.read += count;
+            filled += count;
+        }
+        Ok(())
+    }
+
+    /// Add the next `blocks` of the music to their mix, and to their music
+    /// stem if they have one.
+    pub fn mix_into(&mut self, blocks: &mut [Block]) -> Result<(), String> {
+        let mut buffer = std::mem::take(&mut self.block);
+        for block in blocks {
+            buffer.resize(block.mix.len(), 0.0);
+            self.read(&mut buffer)?;
+            for (out, sample) in block.mix.iter_mut().zip(&buffer) {
+                *out += sample * self.volume;
+            }
+            if let Some(stem) = block.stems.get_mut(MUSIC_KEY) {
+                for (out, sample) in stem.iter_mut().zip(&buffer) {
+                    *out += sample * self.volume;
+                }
+            }
+        }
+        self.block = buffer;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use std::io::Cursor;
+
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+    use super::super::stretch::tests::{onset, onset_error};
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    /// A 16-bit stereo WAV data URL of a 440 Hz tone at 44.1 kHz, silent for
+    /// the first two of its four seconds.
+    fn music() -> String {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut bytes = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut bytes, spec).unwrap();
+        for i in 0..44100 * 4 {
+            let time = i as f32 / 44100.0;
+            let value = if time < 2.0 {
+                0.0
+            } else {
+                0.5 * (2.0 * PI * 440.0 * time).sin()
+            };
+            for _ in 0..2 {
+                writer.write_sample((value * 32767.0) as i16).unwrap();
+            }
+        }
+        writer.finalize().unwrap();
+        format!(
+            "data:audio/wav;base64,{}",
+            STANDARD.encode(bytes.into_inner())
+        )
+    }
+
+    /// The first `seconds` of the mix timeline, checked against the stem.
+    fn mix(stream: &mut MusicStream, seconds: f64) -> Vec<f32> {
+        let mut block = Block::new(&[MUSIC_KEY]);
+        block.mix = vec![0.0; (seconds * SAMPLE_RATE as f64) as usize * 2];
+        block.stems.insert(MUSIC_KEY, block.mix.clone());
+        stream.mix_into(std::slice::from_mut(&mut block)).unwrap();
+        assert_eq!(block.mix, block.stems[MUSIC_KEY]);
+        block.mix
+    }
+
+    #[test]
+    fn music_is_placed_on_the_timeline() {
+        let data = music();
+        // Offsets are given at normal speed, as in `MusicTrack`
+        for (rate, offset) in [(1.0, 0.5), (2.0, -0.5), (0.5, 1.0)] {
+            let frames = (offset / rate * SAMPLE_RATE as f64).round() as i64;
+            let mut stream = MusicStream::open(
+                &data,
+                1.0,
+                SAMPLE_RATE,
+                ResampleQuality::default(),
+                rate,
+                frames,
+            )
+            .unwrap();
+            let length = (4.0 + offset) / rate;
+            let output = mix(&mut stream, length + 1.0);
+            let expected = (2.0 + offset) / rate;
+            let onset = onset(
+                &output[..(length * SAMPLE_RATE as f64) as usize * 2],
+                SAMPLE_RATE,
+            );
+            assert!(
+                onset_error(rate).contains(&(onset - expected)),
+                "{} at {}: {} instead of {}",
+                rate,
+                offset,
+                onset,
+                expected
+            );
+            // Silent after the end of the music
+            let end = (length * SAMPLE_RATE as f64) as usize * 2 + 1000;
+            assert!(output[end..].iter().all(|&sample| sample == 0.0));
+
+            stream.rewind().unwrap();
+            assert_eq!(mix(&mut stream, length + 1.0), output);
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! Onset detection and tempo estimation, used to check a chart's offset
+//! against its music.
+//!
+//! The onset strength is the rise in log energy of three bands (bass, mids
+//! and highs) every `HOP_SECONDS`, less its local average. Onsets are the
+//! prominent peaks of it, the tempo is the strongest period of its
+//! autocorrelation, and the offset correction is the shift of the note
+//! times that lines them up best with it.
+
+/// Time resolution of the analysis in seconds, rounded to whole frames.
+const HOP_SECONDS: f64 = 0.002;
+
+/// Energies below this level in dB count as silence.
+const FLOOR_DB: f32 = -60.0;
+
+/// Half-width in seconds of the local average removed from the strength.
+const AVERAGE_SECONDS: f64 = 0.1;
+
+/// Half-width in seconds within which an onset must be the strongest peak.
+const PEAK_SECONDS: f64 = 0.03;
+
+/// Distance in seconds within which a note counts as matching an onset.
+const MATCH_SECONDS: f64 = 0.03;
+
+/// Distance in seconds from the best shift beyond which other shifts count
+/// as competing candidates.
+const COMPETITOR_SECONDS: f64 = 0.02;
+
+/// Optional behaviour of `analyze_offset`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OffsetOptions {
+    /// Largest offset correction in seconds searched in either direction.
+    pub max_shift: f64,
+    /// Tempo range in BPM searched for the tempo estimate.
+    pub min_bpm: f64,
+    pub max_bpm: f64,
+}
+
+impl Default for OffsetOptions {
+    fn default() -> Self {
+        Self {
+            max_shift: 0.2,
+            min_bpm: 60.0,
+            max_bpm: 240.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OffsetAnalysis {
+    /// Estimated tempo, or `None` if the music has no clear beat.
+    pub bpm: Option<f64>,
+    /// Strength of the tempo's periodicity (0.0 - 1.0).
+    pub bpm_confidence: f64,
+    /// Times of the detected onsets in seconds.
+    pub onsets: Vec<f64>,
+    /// Seconds to add to the note times (or to the chart offset) to line
+    /// the notes up with the music.
+    pub offset_correction: f64,
+    /// How much to trust the correction (0.0 - 1.0): the share of matched
+    /// notes, scaled down when other shifts fit almost as well.
+    pub confidence: f64,
+    /// Share of notes with an onset within 30 ms once corrected.
+    pub matched_notes: f64,
+}
+
+#[derive(Clone, Copy)]
+enum FilterKind {
+    Lowpass,
+    Bandpass,
+    Highpass,
+}
+
+/// RBJ biquad filter.
+struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+impl Biquad {
+    fn new(kind: FilterKind, freq: f64, sample_rate: u32) -> Self {
+        let w = 2.0 * std::f64::consts::PI * freq / sample_rate as f64;
+        let alpha = w.sin() / std::f64::consts::SQRT_2;
+        let cos = w.cos();
+        let (b, a0) = match kind {
+            FilterKind::Lowpass => (
+                [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+                1.0 + alpha,
+            ),
+            FilterKind::Bandpass => ([alpha, 0.0, -alpha], 1.0 + alpha),
+            FilterKind::Highpass => (
+                [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+                1.0 + alpha,
+            ),
+        };
+        Self {
+            b: b.map(|b| (b / a0) as f32),
+            a: [(-2.0 * cos / a0) as f32, ((1.0 - alpha) / a0) as f32],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// Onset strength of interleaved `samples` per `hop` frames.
+fn onset_strength(samples: &[f32], channels: usize, sample_rate: u32, hop: usize) -> Vec<f32> {
+    let channels = channels.max(1);
+    let mut bands = [
+        Biquad::new(FilterKind::Lowpass, 150.0, sample_rate),
+        Biquad::new(FilterKind::Bandpass, 800.0, sample_rate),
+        Biquad::new(FilterKind::Highpass, 3000.0, sample_rate),
+    ];
+
+    let mut previous = [FLOOR_DB; 3];
+    let mut flux = Vec::with_capacity(samples.len() / channels / hop + 1);
+    for chunk in samples.chunks(hop * channels) {
+        let mut energy = [0.0f32; 3];
+        for frame in chunk.chunks_exact(channels) {
+            let value = frame.iter().sum::<f32>() / channels as f32;
+            for (band, energy) in bands.iter_mut().zip(&mut energy) {
+                let filtered = band.process(value);
+                *energy += filtered * filtered;
+            }
+        }
+        let mut rise = 0.0;
+        for (energy, previous) in energy.iter().zip(&mut previous) {
+            let level = (10.0 * (energy / (chunk.len() / channels) as f32).log10()).max(FLOOR_DB);
+            rise += (level - *previous).max(0.0);
+            *previous = level;
+        }
+        flux.push(rise);
+    }
+
+    // Remove the local average so that only rises standing out count
+    let radius = (AVERAGE_SECONDS / HOP_SECONDS) as usize;
+    let mut prefix = vec![0.0f64; flux.len() + 1];
+    for (i, &value) in flux.iter().enumerate() {
+        prefix[i + 1] = prefix[i] + value as f64;
+    }
+    (0..flux.len())
+        .map(|i| {
+            let (low, high) = (i.saturating_sub(radius), (i + radius + 1).min(flux.len()));
+            let average = (prefix[high] - prefix[low]) / (high - low) as f64;
+            (flux[i] - average as f32).max(0.0)
+        })
+        .collect()
+}
+
+/// Times of the peaks of `strength` above its mean plus one standard
+/// deviation that are the strongest within `PEAK_SECONDS`.
+fn pick_onsets(strength: &[f32], hop_seconds: f64) -> Vec<f64> {
+    if strength.is_empty() {
+        return Vec::new();
+    }
+    let mean = strength.iter().sum::<f32>() / strength.len() as f32;
+    let deviation =
+        (strength.iter().map(|s| (s - mean).powi(2)).sum::<f32>() / strength.len() as f32).sqrt();
+    let threshold = mean + deviation;
+    let radius = (PEAK_SECONDS / hop_seconds) as usize;
+
+    (0..strength.len())
+        .filter(|&i| {
+            let (low, high) = (
+                i.saturating_sub(radius),
+                (i + radius + 1).min(strength.len()),
+            );
+            strength[i] > threshold
+                && strength[low..high]
+                    .iter()
+                    .enumerate()
+                    .all(|(j, &s)| s < strength[i] || (s == strength[i] && low + j >= i))
+        })
+        .map(|i| i as f64 * hop_seconds)
+        .collect()
+}
+
+/// Offset of the vertex of the parabola through three neighbouring values,
+/// in (-0.5, 0.5) for a peak in the middle.
+fn parabolic_peak(left: f64, center: f64, right: f64) -> f64 {
+    let curvature = left - 2.0 * center + right;
+    if curvature < 0.0 {
+        (0.5 * (left - right) / curvature).clamp(-0.5, 0.5)
+    } else {
+        0.0
+    }
+}
+
+/// Tempo in BPM within the given range and the strength of its period,
+/// from the autocorrelation of `strength`. Periods are weighted towards
+/// 120 BPM to settle between multiples of the beat.
+fn estimate_tempo(
+    strength: &[f32],
+    hop_seconds: f64,
+    min_bpm: f64,
+    max_bpm: f64,
+) -> (Option<f64>, f64) {
+    let mean = strength.iter().sum::<f32>() / strength.len().max(1) as f32;
+    let centered: Vec<f32> = strength.iter().map(|s| s - mean).collect();
+    let autocorrelation = |lag: usize| -> f64 {
+        centered
+            .iter()
+            .zip(&centered[lag..])
+            .map(|(a, b)| (a * b) as f64)
+            .sum()
+    };
+    let energy = autocorrelation(0);
+    let min_lag = (60.0 / max_bpm / hop_seconds).floor().max(1.0) as usize;
+    let max_lag = (60.0 / min_bpm / hop_seconds).ceil() as usize;
+    if energy <= 0.0 || max_lag + 2 >= centered.len() {
+        return (None, 0.0);
+    }
+
+    let values: Vec<f64> = (min_lag - 1..=max_lag + 1).map(autocorrelation).collect();
+    let weight = |lag: usize| {
+        let octaves = (60.0 / (lag as f64 * hop_seconds) / 120.0).log2();
+        (-0.5 * octaves * octaves).exp()
+    };
+    let Some(best) = (1..values.len() - 1)
+        .filter(|&i| values[i] > 0.0 && values[i] >= values[i - 1] && values[i] >= values[i + 1])
+        .max_by(|&a, &b| {
+            (values[a] * weight(min_lag - 1 + a)).total_cmp(&(values[b] * weight(min_lag - 1 + b)))
+        })
+    else {
+        return (None, 0.0);
+    };
+
+    let lag = (min_lag - 1 + best) as f64
+        + parabolic_peak(values[best - 1], values[best], values[best + 1]);
+    (
+        Some(60.0 / (lag * hop_seconds)),
+        (values[best] / energy).clamp(0.0, 1.0),
+    )
+}
+
+/// Detect onsets and the tempo of the decoded music and compare the onsets
+/// with the chart's `note_times` (in seconds of the music).
+pub fn analyze(
+    samples: &[f32],
+    channels: usize,
+    sample_rate: u32,
+    note_times: &[f64],
+    options: &OffsetOptions,
+) -> Result<OffsetAnalysis, String> {
+    let mut notes: Vec<f64> = note_times
+        .iter()
+        .copied()
+        .filter(|t| t.is_finite())
+        .collect();
+    notes.sort_by(f64::total_cmp);
+    notes.dedup();
+    if notes.is_empty() {
+        return Err("No note times to compare the music against".to_string());
+    }
+    if options.min_bpm <= 0.0 || options.max_bpm <= options.min_bpm {
+        return Err("The BPM range must be positive and non-empty".to_string());
+    }
+
+    let hop = ((sample_rate as f64 * HOP_SECONDS).round() as usize).max(1);
+    let hop_seconds = hop as f64 / sample_rate as f64;
+    let strength = onset_strength(samples, channels, sample_rate, hop);
+    let onsets = pick_onsets(&strength, hop_seconds);
+    let (bpm, bpm_confidence) =
+        estimate_tempo(&strength, hop_seconds, options.min_bpm, options.max_bpm);
+
+    // Correlate the note times with the strength at every shift in range
+    let sample = |time: f64| -> f64 {
+        let position = time / hop_seconds;
+        let index = position.floor();
+        if index < 0.0 || index as usize + 1 >= strength.len() {
+            return 0.0;
+        }
+        let (index, fraction) = (index as usize, (position - index) as f32);
+        (strength[index] * (1.0 - fraction) + strength[index + 1] * fraction) as f64
+    };
+    let max_steps = (options.max_shift.max(0.0) / hop_seconds).round() as i64;
+    let scores: Vec<f64> = (-max_steps..=max_steps)
+        .map(|step| {
+            let shift = step as f64 * hop_seconds;
+            notes.iter().map(|&note| sample(note + shift)).sum()
+        })
+        .collect();
+    let best = (0..scores.len())
+        .max_by(|&a, &b| scores[a].total_cmp(&scores[b]))
+        .unwrap();
+
+    let (offset_correction, confidence) = if scores[best] > 0.0 {
+        let refinement = if best > 0 && best + 1 < scores.len() {
+            parabolic_peak(scores[best - 1], scores[best], scores[best + 1])
+        } else {
+            0.0
+        };
+        let shift = ((best as i64 - max_steps) as f64 + refinement) * hop_seconds;
+        let exclusion = (COMPETITOR_SECONDS / hop_seconds) as usize;
+        let competitor = scores
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i.abs_diff(best) > exclusion)
+            .map(|(_, &score)| score)
+            .fold(0.0, f64::max);
+        (shift, 1.0 - competitor / scores[best])
+    } else {
+        (0.0, 0.0)
+    };
+
+    let matched = notes
+        .iter()
+        .filter(|&&note| {
+            let time = note + offset_correction;
+            let index = onsets.partition_point(|&onset| onset < time);
+            [index.checked_sub(1), Some(index)]
+                .into_iter()
+                .flatten()
+                .filter_map(|i| onsets.get(i))
+                .any(|onset| (onset - time).abs() <= MATCH_SECONDS)
+        })
+        .count();
+    let matched_notes = matched as f64 / notes.len() as f64;
+
+    Ok(OffsetAnalysis {
+        bpm,
+        bpm_confidence,
+        onsets,
+        offset_correction,
+        confidence: matched_notes * confidence,
+        matched_notes,
+    })
+}
@@ -0,0 +1,392 @@
+//! Low-latency preview playback of preloaded sounds, for frontends where
+//! WebAudio lags.
+//!
+//! Sounds are scheduled on a playback clock that advances as the output
+//! renders blocks of the player and can be moved with `seek`. The output is
+//! a rodio stream on the default device, or a null sink consuming samples
+//! in real time when `AUDIO_OUTPUT=null` is set or no device is available,
+//! so that playback also runs headless.
+
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use rodio::Source;
+
+use super::SAMPLE_RATE;
+
+/// Frames mixed at a time. Bounds the scheduling granularity of the
+/// output thread on top of the device buffer.
+const BLOCK_FRAMES: usize = 128;
+
+struct Voice {
+    key: String,
+    samples: Arc<Vec<f32>>,
+    /// Clock frame at which the voice starts.
+    start: i64,
+    /// Position in frames within `samples`.
+    position: f64,
+    volume: f32,
+    rate: f64,
+}
+
+/// Sounds, voices and clock of the preview, independent of where its
+/// output goes.
+#[derive(Default)]
+pub struct Player {
+    sounds: HashMap<String, Arc<Vec<f32>>>,
+    voices: Vec<Voice>,
+    /// Frames rendered since clock time zero.
+    clock: i64,
+}
+
+/// The player behind the module functions, fed to the first output started.
+static PLAYER: LazyLock<Arc<Mutex<Player>>> =
+    LazyLock::new(|| Arc::new(Mutex::new(Player::default())));
+
+/// Name of the running output, set once it has been started.
+static OUTPUT: OnceLock<&'static str> = OnceLock::new();
+
+impl Player {
+    /// Make decoded sounds available to `play`, replacing sounds with the
+    /// same keys.
+    pub fn preload(&mut self, sounds: Vec<(String, Arc<Vec<f32>>)>) {
+        self.sounds.extend(sounds);
+    }
+
+    /// Play sound `key` at clock time `at` in seconds, or right away. A
+    /// sound scheduled in the past still starts right away unless it would
+    /// already have ended.
+    pub fn play(
+        &mut self,
+        key: &str,
+        at: Option<f64>,
+        volume: f32,
+        rate: f32,
+    ) -> Result<(), String> {
+        if !rate.is_finite() || rate <= 0.0 {
+            return Err(format!("Invalid playback rate: {}", rate));
+        }
+        let samples = self
+            .sounds
+            .get(key)
+            .cloned()
+            .ok_or_else(|| format!("Sound {} is not preloaded", key))?;
+        let start = match at {
+            Some(at) => (at * SAMPLE_RATE as f64).round() as i64,
+            None => self.clock,
+        };
+        let length = (samples.len() / 2) as f64 / rate as f64;
+        if (start as f64 + length) < self.clock as f64 {
+            return Ok(());
+        }
+        self.voices.push(Voice {
+            key: key.to_string(),
+            samples,
+            start,
+            position: 0.0,
+            volume,
+            rate: rate as f64,
+        });
+        Ok(())
+    }
+
+    /// Stop the voices of sound `key`, or all voices.
+    pub fn stop(&mut self, key: Option<&str>) {
+        match key {
+            Some(key) => self.voices.retain(|voice| voice.key != key),
+            None => self.voices.clear(),
+        }
+    }
+
+    /// Move the clock to `time` in seconds, stopping all voices.
+    pub fn seek(&mut self, time: f64) {
+        self.voices.clear();
+        self.clock = (time * SAMPLE_RATE as f64).round() as i64;
+    }
+
+    /// Free the preloaded sounds `keys`, or all of them, stopping their
+    /// voices.
+    pub fn unload(&mut self, keys: Option<&[String]>) {
+        match keys {
+            Some(keys) => {
+                self.sounds.retain(|key, _| !keys.contains(key));
+                self.voices.retain(|voice| !keys.contains(&voice.key));
+            }
+            None => {
+                self.sounds.clear();
+                self.voices.clear();
+            }
+        }
+    }
+
+    /// Mix the next block of the voices into `block`, advancing the clock.
+    fn render(&mut self, block: &mut [f32]) {
+        block.fill(0.0);
+        let frames = block.len() / 2;
+        let clock = self.clock;
+
+        self.voices.retain_mut(|voice| {
+            let offset = (voice.start - clock).max(0) as usize;
+            if offset >= frames {
+                return true;
+            }
+            let length = voice.samples.len() / 2;
+            let sample = |frame: usize, channel: usize| -> f32 {
+                voice
+                    .samples
+                    .get(frame * 2 + channel)
+                    .copied()
+                    .unwrap_or(0.0)
+            };
+            for frame in offset..frames {
+                let index = voice.position as usize;
+                if index >= length {
+                    return false;
+                }
+                // Linear interpolation is enough for preview playback rates
+                let fraction = (voice.position - index as f64) as f32;
+                for channel in 0..2 {
+                    let value = sample(index, channel) * (1.0 - fraction)
+                        + sample(index + 1, channel) * fraction;
+                    block[frame * 2 + channel] += value * voice.volume;
+                }
+                voice.position += voice.rate;
+            }
+            true
+        });
+
+        for sample in block.iter_mut() {
+            *sample = sample.clamp(-1.0, 1.0);
+        }
+        self.clock += frames as i64;
+    }
+}
+
+/// The rodio source pulling blocks from a player.
+struct Output {
+    player: Arc<Mutex<Player>>,
+    block: Vec<f32>,
+    index: usize,
+}
+
+impl Output {
+    fn new(player: Arc<Mutex<Player>>) -> Self {
+        Self {
+            player,
+            block: vec![0.0; BLOCK_FRAMES * 2],
+            index: BLOCK_FRAMES * 2,
+        }
+    }
+}
+
+impl Iterator for Output {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.index == self.block.len() {
+            self.player.lock().unwrap().render(&mut self.block);
+            self.index = 0;
+        }
+        self.index += 1;
+        Some(self.block[self.index - 1])
+    }
+}
+
+impl Source for Output {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Play the output of `player` on the default device from a thread keeping
+/// the stream, which cannot leave the thread it was created on, alive.
+fn start_device(player: Arc<Mutex<Player>>) -> Result<(), String> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let stream = rodio::OutputStream::try_default()
+            .map_err(|e| e.to_string())
+            .and_then(|(stream, handle)| {
+                handle
+                    .play_raw(Output::new(player))
+                    .map_err(|e| e.to_string())?;
+                Ok(stream)
+            });
+        let started = stream.is_ok();
+        let _ = sender.send(stream.map(|_| ()));
+        if started {
+            loop {
+                std::thread::park();
+            }
+        }
+    });
+    receiver.recv().map_err(|e| e.to_string())?
+}
+
+/// Consume the output of `player` in real time without playing it.
+fn start_null_sink(player: Arc<Mutex<Player>>) {
+    std::thread::spawn(move || {
+        let mut output = Output::new(player);
+        let start = Instant::now();
+        let mut frames = 0u64;
+        loop {
+            for _ in 0..BLOCK_FRAMES * 2 {
+                output.next();
+            }
+            frames += BLOCK_FRAMES as u64;
+            let due = start + Duration::from_secs_f64(frames as f64 / SAMPLE_RATE as f64);
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+    });
+}
+
+/// Start the output if it is not running yet, returning `"device"` or
+/// `"null"`.
+fn ensure_output() -> &'static str {
+    OUTPUT.get_or_init(|| {
+        if std::env::var("AUDIO_OUTPUT").is_ok_and(|output| output == "null") {
+            start_null_sink(PLAYER.clone());
+            return "null";
+        }
+        match start_device(PLAYER.clone()) {
+            Ok(()) => "device",
+            Err(e) => {
+                eprintln!("[TAURI] No audio output ({}), playing to a null sink", e);
+                start_null_sink(PLAYER.clone());
+                "null"
+            }
+        }
+    })
+}
+
+/// Make decoded sounds available to `play`, replacing sounds with the same
+/// keys, and start the output. The sounds stay in memory until unloaded.
+/// Returns the kind of output in use.
+pub fn preload(sounds: Vec<(String, Arc<Vec<f32>>)>) -> &'static str {
+    PLAYER.lock().unwrap().preload(sounds);
+    ensure_output()
+}
+
+/// Play a preloaded sound, as `Player::play` does.
+pub fn play(key: &str, at: Option<f64>, volume: f32, rate: f32) -> Result<(), String> {
+    PLAYER.lock().unwrap().play(key, at, volume, rate)
+}
+
+/// Stop the voices of sound `key`, or all voices.
+pub fn stop(key: Option<&str>) {
+    PLAYER.lock().unwrap().stop(key);
+}
+
+/// Move the clock to `time` in seconds, stopping all voices.
+pub fn seek(time: f64) {
+    PLAYER.lock().unwrap().seek(time);
+}
+
+/// Free the preloaded sounds `keys`, or all of them, stopping their voices.
+pub fn unload(keys: Option<&[String]>) {
+    PLAYER.lock().unwrap().unload(keys);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A player with 50 ms of a constant signal preloaded as `a`.
+    fn player() -> Player {
+        let mut player = Player::default();
+        let samples = Arc::new(vec![0.5; SAMPLE_RATE as usize / 10]);
+        player.preload(vec![("a".to_string(), samples)]);
+        player
+    }
+
+    /// Render `frames` frames, returning the left channel.
+    fn render(player: &mut Player, frames: usize) -> Vec<f32> {
+        let mut block = vec![0.0; frames * 2];
+        player.render(&mut block);
+        block.into_iter().step_by(2).collect()
+    }
+
+    #[test]
+    fn voices_play_and_retire() {
+        let mut player = player();
+        assert!(player.play("b", None, 1.0, 1.0).is_err());
+        assert!(player.play("a", None, 1.0, 0.0).is_err());
+
+        player.play("a", None, 1.0, 1.0).unwrap();
+        let left = render(&mut player, SAMPLE_RATE as usize / 10);
+        let end = SAMPLE_RATE as usize / 20;
+        assert!(left[..end].iter().all(|&s| s == 0.5));
+        assert!(left[end..].iter().all(|&s| s == 0.0));
+        assert!(player.voices.is_empty());
+        assert_eq!(player.clock, SAMPLE_RATE as i64 / 10);
+    }
+
+    #[test]
+    fn voices_start_on_the_clock() {
+        let mut player = player();
+        // Double speed from 10 ms on, over a voice playing already
+        player.play("a", Some(0.01), 0.5, 2.0).unwrap();
+        player.play("a", None, 0.5, 1.0).unwrap();
+        let start = SAMPLE_RATE as usize / 100;
+        let left = render(&mut player, SAMPLE_RATE as usize / 50);
+        assert_eq!(left[start - 1], 0.25);
+        assert_eq!(left[start], 0.5);
+
+        // The double speed voice ends 25 ms after its start
+        let end = start + SAMPLE_RATE as usize / 40 - SAMPLE_RATE as usize / 50;
+        let left = render(&mut player, SAMPLE_RATE as usize / 50);
+        assert_eq!(left[end - 2], 0.5);
+        assert_eq!(left[end + 1], 0.25);
+        assert_eq!(player.voices.len(), 1);
+    }
+
+    #[test]
+    fn voices_are_stopped_seeked_and_unloaded() {
+        let mut player = player();
+        player.play("a", Some(60.0), 1.0, 1.0).unwrap();
+        player.play("a", Some(60.0), 1.0, 2.0).unwrap();
+        player.stop(Some("b"));
+        assert_eq!(player.voices.len(), 2);
+        player.stop(Some("a"));
+        assert!(player.voices.is_empty());
+
+        // Seeking moves the clock and stops everything
+        player.play("a", Some(60.0), 1.0, 1.0).unwrap();
+        player.seek(60.0);
+        assert!(player.voices.is_empty());
+        assert_eq!(player.clock, 60 * SAMPLE_RATE as i64);
+
+        // A sound scheduled in the past is skipped if it would have ended,
+        // and starts right away otherwise
+        player.play("a", Some(59.0), 1.0, 1.0).unwrap();
+        assert!(player.voices.is_empty());
+        player.play("a", Some(59.99), 1.0, 1.0).unwrap();
+        assert_eq!(render(&mut player, 1), [0.5]);
+
+        player.unload(None);
+        assert!(player.voices.is_empty());
+        assert!(player.play("a", None, 1.0, 1.0).is_err());
+    }
+
+    #[test]
+    fn output_pulls_blocks_from_its_player() {
+        let player = Arc::new(Mutex::new(player()));
+        player.lock().unwrap().play("a", None, 1.0, 1.0).unwrap();
+        let mut output = Output::new(player.clone());
+        let samples: Vec<f32> = output.by_ref().take(BLOCK_FRAMES * 2 + 2).collect();
+        assert!(samples.iter().all(|&s| s == 0.5));
+        assert_eq!(player.lock().unwrap().clock, BLOCK_FRAMES as i64 * 2);
+    }
+}
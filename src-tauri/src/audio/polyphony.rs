@@ -0,0 +1,162 @@
+//! Polyphony control applied to the voices before mixing: merging of
+//! near-simultaneous identical hits and per-sound and global voice limits.
+
+use std::collections::HashMap;
+
+use super::mixer::Voice;
+
+/// Which sounding voice gets cut when a new one exceeds a voice limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VoiceStealing {
+    /// The voice that started first.
+    #[default]
+    Oldest,
+    /// The voice with the lowest volume, the oldest one among equals.
+    Quietest,
+}
+
+/// Merge voices of the same sound at the same rate whose starts fall within
+/// `window` frames of the first voice of their group into that voice,
+/// summing the volumes. The pan of a merged voice is the volume-weighted
+/// average.
+pub fn merge(mut voices: Vec<Voice<'_>>, window: usize) -> Vec<Voice<'_>> {
+    voices.sort_by_key(|voice| voice.start);
+
+    let mut merged: Vec<Voice> = Vec::with_capacity(voices.len());
+    // Index in `merged` of the latest group per sound key and rate bits
+    let mut groups: HashMap<(&str, u32), usize> = HashMap::new();
+    for voice in voices {
+        let id = (voice.key, voice.rate.to_bits());
+        if let Some(&index) = groups.get(&id) {
+            let leader = &mut merged[index];
+            if voice.start - leader.start <= window {
+                let volume = leader.volume + voice.volume;
+                if volume != 0.0 {
+                    leader.pan = (leader.pan * leader.volume + voice.pan * voice.volume) / volume;
+                }
+                leader.volume = volume;
+                continue;
+            }
+        }
+        groups.insert(id, merged.len());
+        merged.push(voice);
+    }
+    merged
+}
+
+/// Enforce at most `per_sound` sounding voices per sound key and `global`
+/// sounding voices overall. When a new voice would exceed a limit, a voice
+/// chosen by `stealing` is cut, fading out over `release` frames.
+pub fn limit(
+    voices: &mut [Voice<'_>],
+    per_sound: Option<usize>,
+    global: Option<usize>,
+    stealing: VoiceStealing,
+    release: usize,
+) {
+    if per_sound.is_none() && global.is_none() {
+        return;
+    }
+    voices.sort_by_key(|voice| voice.start);
+
+    // Indices of the voices sounding at the start of the current one
+    let mut active: Vec<usize> = Vec::new();
+    for i in 0..voices.len() {
+        let start = voices[i].start;
+        active.retain(|&j| voices[j].end() > start);
+
+        if let Some(limit) = per_sound {
+            let key = voices[i].key;
+            while active.iter().filter(|&&j| voices[j].key == key).count() >= limit {
+                let candidates = active.iter().copied().filter(|&j| voices[j].key == key);
+                let victim = choose_victim(voices, candidates, stealing);
+                steal(voices, &mut active, victim, start, release);
+            }
+        }
+        if let Some(limit) = global {
+            while active.len() >= limit {
+                let victim = choose_victim(voices, active.iter().copied(), stealing);
+                steal(voices, &mut active, victim, start, release);
+            }
+        }
+
+        active.push(i);
+    }
+}
+
+fn choose_victim(
+    voices: &[Voice<'_>],
+    candidates: impl Iterator<Item = usize>,
+    stealing: VoiceStealing,
+) -> usize {
+    // Indices grow with the start frame, so the first minimum is the oldest
+    match stealing {
+        VoiceStealing::Oldest => candidates.min(),
+        VoiceStealing::Quietest => {
+            candidates.min_by(|&a, &b| voices[a].volume.total_cmp(&voices[b].volume))
+        }
+    }
+    .unwrap()
+}
+
+/// Cut the voice at `victim` so that it fades out starting at frame `at`,
+/// and stop counting it as sounding.
+fn steal(
+    voices: &mut [Voice<'_>],
+    active: &mut Vec<usize>,
+    victim: usize,
+    at: usize,
+    release: usize,
+) {
+    let voice = &mut voices[victim];
+    let frames = (at - voice.start + release).min(voice.samples.len() / 2);
+    voice.samples = &voice.samples[..frames * 2];
+    voice.release = release.min(frames);
+    active.retain(|&j| j != victim);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voice<'a>(key: &'a str, rate: f32, samples: &'a [f32], start: usize) -> Voice<'a> {
+        Voice {
+            key,
+            rate,
+            samples,
+            start,
+            volume: 0.5,
+            pan: 0.0,
+            release: 0,
+        }
+    }
+
+    #[test]
+    fn merges_by_key_and_rate() {
+        let samples = [0.5; 200];
+        let voices = vec![
+            voice("tap", 1.0, &samples, 0),
+            voice("tap", 1.0, &samples, 5),
+            // The same samples under another key or at another rate
+            voice("drag", 1.0, &samples, 5),
+            voice("tap", 1.5, &samples, 5),
+            // Outside the window of the first voice
+            voice("tap", 1.0, &samples, 20),
+        ];
+        let merged = merge(voices, 10);
+        let summary: Vec<_> = merged
+            .iter()
+            .map(|voice| (voice.key, voice.rate, voice.start, voice.volume))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("tap", 1.0, 0, 1.0),
+                ("drag", 1.0, 5, 0.5),
+                ("tap", 1.5, 5, 0.5),
+                ("tap", 1.0, 20, 0.5),
+            ]
+        );
+    }
+}
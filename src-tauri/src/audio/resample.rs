@@ -0,0 +1,284 @@
+//! Resampling of interleaved stereo audio, used both for converting decoded
+//! sounds to the mixer rate and for per-timestamp playback rates.
+
+use std::f64::consts::PI;
+
+/// Number of fractional positions the sinc kernel is tabulated at. Positions
+/// in between are linearly interpolated from the two nearest rows.
+const PHASES: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResampleQuality {
+    /// Linear interpolation. Fast, but aliases audibly.
+    Linear,
+    /// Kaiser-windowed sinc with 16 zero crossings per side.
+    #[default]
+    Medium,
+    /// Kaiser-windowed sinc with 48 zero crossings per side.
+    High,
+}
+
+impl ResampleQuality {
+    /// Zero crossings per side and Kaiser beta of the sinc kernel.
+    fn kernel(self) -> Option<(usize, f64)> {
+        match self {
+            Self::Linear => None,
+            Self::Medium => Some((16, 8.0)),
+            Self::High => Some((48, 10.0)),
+        }
+    }
+}
+
+/// Interpolation between input frames.
+enum Kernel {
+    Linear,
+    Sinc {
+        /// Taps on each side of the interpolated position.
+        half: usize,
+        table: Vec<f32>,
+        /// Coefficients of the current position, reused between frames.
+        coefficients: Vec<f32>,
+    },
+}
+
+impl Kernel {
+    fn new(step: f64, quality: ResampleQuality) -> Self {
+        match quality.kernel() {
+            None => Self::Linear,
+            Some((zero_crossings, beta)) => {
+                // Lower the cutoff below the output Nyquist frequency when
+                // downsampling, widening the kernel accordingly.
+                let cutoff = (1.0 / step).min(1.0);
+                let half = (zero_crossings as f64 / cutoff).ceil() as usize;
+                Self::Sinc {
+                    half,
+                    table: kernel_table(half, cutoff, beta),
+                    coefficients: vec![0.0; half * 2],
+                }
+            }
+        }
+    }
+
+    /// Input frames needed after the one at or before a position.
+    fn lookahead(&self) -> usize {
+        match self {
+            Self::Linear => 1,
+            Self::Sinc { half, .. } => *half,
+        }
+    }
+
+    /// Input frames needed before the one at or before a position.
+    fn lookbehind(&self) -> usize {
+        match self {
+            Self::Linear => 0,
+            Self::Sinc { half, .. } => half - 1,
+        }
+    }
+
+    /// Interpolate the stereo frame at input `position` from `samples`,
+    /// which hold the input frames `start..end`. Frames outside the input
+    /// count as silence.
+    fn frame(&mut self, samples: &[f32], start: usize, end: usize, position: f64) -> [f32; 2] {
+        match self {
+            Self::Linear => {
+                let index = position.floor() as usize;
+                let frac = (position - index as f64) as f32;
+                let next = (index + 1).min(end - 1);
+                let (index, next) = (index - start, next - start);
+                [0, 1].map(|channel| {
+                    let s1 = samples[index * 2 + channel];
+                    let s2 = samples[next * 2 + channel];
+                    s1 * (1.0 - frac) + s2 * frac
+                })
+            }
+            Self::Sinc {
+                half,
+                table,
+                coefficients,
+            } => {
+                let taps = *half * 2;
+                let base = position.floor() as isize;
+                let phase = (position - base as f64) * PHASES as f64;
+                let row = (phase.floor() as usize).min(PHASES - 1);
+                let blend = (phase - row as f64) as f32;
+                let (lower, upper) = (&table[row * taps..], &table[(row + 1) * taps..]);
+                for (j, c) in coefficients.iter_mut().enumerate() {
+                    *c = lower[j] + (upper[j] - lower[j]) * blend;
+                }
+
+                // Taps cover input frames base - half + 1 ..= base + half
+                let first = base - *half as isize + 1;
+                let (mut left, mut right) = (0.0f32, 0.0f32);
+                for (j, c) in coefficients.iter().enumerate() {
+                    let k = first + j as isize;
+                    if k < start as isize || k as usize >= end {
+                        continue;
+                    }
+                    let k = k as usize - start;
+                    left += samples[k * 2] * c;
+                    right += samples[k * 2 + 1] * c;
+                }
+                [left, right]
+            }
+        }
+    }
+}
+
+/// Resample interleaved stereo `samples`, advancing `step` input frames per
+/// output frame. A step of 2.0 halves the length (and doubles the pitch);
+/// converting from `from` Hz to `to` Hz is a step of `from / to`.
+pub fn resample_stereo(samples: &[f32], step: f64, quality: ResampleQuality) -> Vec<f32> {
+    let frames = samples.len() / 2;
+    if is_identity(step) || frames == 0 {
+        return samples.to_vec();
+    }
+
+    let out_frames = (frames as f64 / step).floor() as usize;
+    let mut kernel = Kernel::new(step, quality);
+    let mut resampled = Vec::with_capacity(out_frames * 2);
+    for i in 0..out_frames {
+        resampled.extend(kernel.frame(samples, 0, frames, i as f64 * step));
+    }
+    resampled
+}
+
+/// Whether resampling by `step` leaves the samples as they are.
+fn is_identity(step: f64) -> bool {
+    step == 1.0 || step <= 0.0 || !step.is_finite()
+}
+
+/// `resample_stereo` over input arriving in chunks, keeping only the
+/// frames still needed by the kernel. The output is the same as resampling
+/// the whole input at once.
+pub struct Resampler {
+    step: f64,
+    kernel: Kernel,
+    /// Buffered input, starting at input frame `offset`.
+    input: Vec<f32>,
+    offset: usize,
+    /// Index of the next output frame.
+    next: usize,
+}
+
+impl Resampler {
+    pub fn new(step: f64, quality: ResampleQuality) -> Self {
+        Self {
+            step,
+            kernel: Kernel::new(step, quality),
+            input: Vec::new(),
+            offset: 0,
+            next: 0,
+        }
+    }
+
+    /// Add the next chunk of interleaved stereo input, appending the output
+    /// frames it completes to `output`.
+    pub fn process(&mut self, samples: &[f32], output: &mut Vec<f32>) {
+        if is_identity(self.step) {
+            output.extend_from_slice(samples);
+            return;
+        }
+        self.input.extend_from_slice(samples);
+        self.run(output, false);
+    }
+
+    /// Append the rest of the output once all input has been added.
+    pub fn finish(&mut self, output: &mut Vec<f32>) {
+        if !is_identity(self.step) {
+            self.run(output, true);
+        }
+    }
+
+    fn run(&mut self, output: &mut Vec<f32>, finished: bool) {
+        let end = self.offset + self.input.len() / 2;
+        // The output length so far, reached for good once the input is
+        // complete
+        let out_frames = (end as f64 / self.step).floor() as usize;
+        loop {
+            let position = self.next as f64 * self.step;
+            let ready = self.next < out_frames
+                && (finished || position.floor() as usize + self.kernel.lookahead() < end);
+            if !ready {
+                break;
+            }
+            output.extend(self.kernel.frame(&self.input, self.offset, end, position));
+            self.next += 1;
+        }
+
+        // Drop the input before the first frame the next output needs
+        let needed = ((self.next as f64 * self.step).floor() as usize)
+            .saturating_sub(self.kernel.lookbehind());
+        let dropped = needed.saturating_sub(self.offset).min(end - self.offset);
+        self.input.drain(..dropped * 2);
+        self.offset += dropped;
+    }
+}
+
+/// Tabulate the windowed sinc kernel for fractional positions
+/// `0, 1/PHASES, ..., 1`, each row holding `2 * half` taps.
+fn kernel_table(half: usize, cutoff: f64, beta: f64) -> Vec<f32> {
+    let taps = half * 2;
+    let norm = bessel_i0(beta);
+    let mut table = Vec::with_capacity((PHASES + 1) * taps);
+    for p in 0..=PHASES {
+        let frac = p as f64 / PHASES as f64;
+        for j in 0..taps {
+            let distance = (j as f64 - half as f64 + 1.0) - frac;
+            let x = distance / half as f64;
+            let window = if x.abs() >= 1.0 {
+                0.0
+            } else {
+                bessel_i0(beta * (1.0 - x * x).sqrt()) / norm
+            };
+            table.push((cutoff * sinc(cutoff * distance) * window) as f32);
+        }
+    }
+    table
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Zeroth-order modified Bessel function of the first kind.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..50 {
+        term *= half / k as f64;
+        let squared = term * term;
+        sum += squared;
+        if squared < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunked_resampling_matches_whole_buffer() {
+        let samples: Vec<f32> = (0..20001).map(|i| (i as f32 * 0.37).sin()).collect();
+        for quality in [ResampleQuality::Linear, ResampleQuality::Medium] {
+            for step in [44100.0 / 48000.0, 0.5, 2.3] {
+                let whole = resample_stereo(&samples, step, quality);
+                let mut resampler = Resampler::new(step, quality);
+                let mut chunked = Vec::new();
+                for chunk in samples.chunks(2 * 777) {
+                    resampler.process(chunk, &mut chunked);
+                }
+                resampler.finish(&mut chunked);
+                assert_eq!(whole, chunked, "{:?} at {}", quality, step);
+            }
+        }
+    }
+}
@@ -0,0 +1,328 @@
+//! Pitch-preserving time-stretching by WSOLA (waveform similarity
+//! overlap-add): windows of the input are overlap-added at a fixed output
+//! hop, each taken from around its nominal input position where it best
+//! continues the previous window. Windows are placed by their centers, so
+//! that an onset stays within the search tolerance of where it belongs,
+//! except that slowing down repeats it: its first, faded copy may come up to
+//! half a window times `1 / rate - 1` early (20 ms at half speed). Input is
+//! processed as it arrives, so that a whole song never has to be held in
+//! memory.
+
+use std::f32::consts::PI;
+
+/// Window length in seconds.
+const WINDOW_SECONDS: f64 = 0.04;
+
+/// Largest shift in seconds searched around the nominal input position.
+const TOLERANCE_SECONDS: f64 = 0.01;
+
+/// Step in frames between candidates and between compared frames in the
+/// coarse search.
+const COARSE_STEP: usize = 4;
+
+/// Stretcher of interleaved stereo input arriving in chunks, so that it
+/// plays `rate` times as fast without changing pitch. The output has
+/// `1 / rate` times as many frames. Only the input still within reach of
+/// the search and the output still being overlap-added are kept.
+pub struct Stretcher {
+    rate: f64,
+    hop: usize,
+    tolerance: usize,
+    hann: Vec<f32>,
+    /// Buffered input and its mono downmix, starting at input frame
+    /// `offset`.
+    input: Vec<f32>,
+    mono: Vec<f32>,
+    offset: usize,
+    /// Overlap-added output, starting at output frame `out_offset`.
+    output: Vec<f32>,
+    out_offset: usize,
+    /// Index of the next window.
+    window_index: usize,
+    /// Input frame the previous window was taken from.
+    previous: usize,
+}
+
+impl Stretcher {
+    pub fn new(rate: f64, sample_rate: u32) -> Self {
+        let window = ((sample_rate as f64 * WINDOW_SECONDS) as usize).max(64) & !1;
+        Self {
+            rate,
+            hop: window / 2,
+            tolerance: (sample_rate as f64 * TOLERANCE_SECONDS) as usize,
+            // Periodic Hann window; two of them at half overlap sum to one
+            hann: (0..window)
+                .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / window as f32).cos())
+                .collect(),
+            input: Vec::new(),
+            mono: Vec::new(),
+            offset: 0,
+            output: Vec::new(),
+            out_offset: 0,
+            window_index: 0,
+            previous: 0,
+        }
+    }
+
+    /// Input frame window `index` is nominally taken from. The center of
+    /// the window is mapped rather than its start, so that a transient lands
+    /// where it belongs whichever part of a window it falls into.
+    fn nominal(&self, index: usize) -> usize {
+        let half = (self.hann.len() / 2) as f64;
+        ((index * self.hop) as f64 * self.rate + half * (self.rate - 1.0))
+            .round()
+            .max(0.0) as usize
+    }
+
+    /// Add the next chunk of input, appending the output frames it
+    /// completes to `output`.
+    pub fn process(&mut self, samples: &[f32], output: &mut Vec<f32>) {
+        if self.rate == 1.0 {
+            output.extend_from_slice(samples);
+            return;
+        }
+        self.input.extend_from_slice(samples);
+        self.mono
+            .extend(samples.chunks_exact(2).map(|frame| frame[0] + frame[1]));
+        self.run(output, false);
+    }
+
+    /// Append the rest of the output once all input has been added.
+    pub fn finish(&mut self, output: &mut Vec<f32>) {
+        if self.rate != 1.0 {
+            self.run(output, true);
+        }
+    }
+
+    fn run(&mut self, output: &mut Vec<f32>, finished: bool) {
+        let (hop, tolerance, window) = (self.hop, self.tolerance, self.hann.len());
+        let end = self.offset + self.mono.len();
+        let out_frames = (end as f64 / self.rate).round() as usize;
+        loop {
+            let out_start = self.window_index * hop;
+            let nominal = self.nominal(self.window_index);
+            // Until the input is complete, a window is only placed once
+            // everything its search can reach has arrived
+            let ready =
+                out_start < out_frames && (finished || nominal + tolerance + window + hop <= end);
+            if !ready {
+                break;
+            }
+
+            let start = if self.window_index == 0 {
+                0
+            } else {
+                self.offset
+                    + best_match(
+                        &self.mono,
+                        self.previous + hop - self.offset,
+                        nominal - self.offset,
+                        tolerance,
+                        hop,
+                    )
+            };
+
+            let needed = (out_start + window - self.out_offset) * 2;
+            if self.output.len() < needed {
+                self.output.resize(needed, 0.0);
+            }
+            for (i, &weight) in self.hann.iter().enumerate() {
+                let src = start + i;
+                if src >= end {
+                    break;
+                }
+                // Nothing precedes the first window, so it starts at full level
+                let weight = if self.window_index == 0 && i < hop {
+                    1.0
+                } else {
+                    weight
+                };
+                let src = (src - self.offset) * 2;
+                let dst = (out_start + i - self.out_offset) * 2;
+                self.output[dst] += self.input[src] * weight;
+                self.output[dst + 1] += self.input[src + 1] * weight;
+            }
+            self.previous = start;
+            self.window_index += 1;
+        }
+
+        // Frames before the next window are complete. Before the end, the
+        // output length is only bounded by the input so far.
+        let complete = (self.window_index * hop).min(out_frames);
+        if complete > self.out_offset {
+            let count = (complete - self.out_offset) * 2;
+            self.output.resize(self.output.len().max(count), 0.0);
+            output.extend(self.output.drain(..count));
+            self.out_offset = complete;
+        }
+
+        // Drop the input the next search cannot reach
+        if self.window_index > 0 {
+            let next_nominal = self.nominal(self.window_index);
+            let needed = self.previous.min(next_nominal.saturating_sub(tolerance));
+            let dropped = needed.saturating_sub(self.offset).min(end - self.offset);
+            self.input.drain(..dropped * 2);
+            self.mono.drain(..dropped);
+            self.offset += dropped;
+        }
+    }
+}
+
+/// Start within `tolerance` of `nominal` of the `length` frames of `mono`
+/// most similar to those at `target`, the natural continuation of the
+/// previous window. Searched coarsely first, then refined around the best
+/// coarse candidate.
+fn best_match(
+    mono: &[f32],
+    target: usize,
+    nominal: usize,
+    tolerance: usize,
+    length: usize,
+) -> usize {
+    let last = match mono.len().checked_sub(length) {
+        Some(last) if target <= last => last,
+        _ => return nominal,
+    };
+    let low = nominal.saturating_sub(tolerance).min(last);
+    let high = (nominal + tolerance).min(last);
+    let reference = &mono[target..target + length];
+
+    let similarity = |candidate: usize, step: usize| -> f32 {
+        let segment = &mono[candidate..candidate + length];
+        let (mut correlation, mut energy) = (0.0, 0.0);
+        for (a, b) in reference.iter().zip(segment).step_by(step) {
+            correlation += a * b;
+            energy += b * b;
+        }
+        if energy > 0.0 {
+            correlation / energy.sqrt()
+        } else {
+            0.0
+        }
+    };
+    let best = |candidates: &mut dyn Iterator<Item = usize>, step: usize| -> usize {
+        candidates
+            .map(|candidate| (candidate, similarity(candidate, step)))
+            // On a tie, as in silence, stay closest to the nominal position
+            .max_by(|a, b| {
+                a.1.total_cmp(&b.1)
+                    .then_with(|| b.0.abs_diff(nominal).cmp(&a.0.abs_diff(nominal)))
+            })
+            .map_or(nominal.min(last), |(candidate, _)| candidate)
+    };
+
+    let coarse = best(&mut (low..=high).step_by(COARSE_STEP), COARSE_STEP);
+    let refine_low = coarse.saturating_sub(COARSE_STEP - 1).max(low);
+    let refine_high = (coarse + COARSE_STEP - 1).min(high);
+    best(&mut (refine_low..=refine_high), 1)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    /// Three seconds of a stereo 440 Hz tone at half scale, silent before
+    /// `onset` seconds.
+    fn tone(onset: f32) -> Vec<f32> {
+        (0..SAMPLE_RATE as usize * 3)
+            .flat_map(|i| {
+                let time = i as f32 / SAMPLE_RATE as f32;
+                let value = if time < onset {
+                    0.0
+                } else {
+                    0.5 * (2.0 * PI * 440.0 * time).sin()
+                };
+                [value, value]
+            })
+            .collect()
+    }
+
+    fn stretch(input: &[f32], rate: f64, chunk_frames: usize) -> Vec<f32> {
+        let mut stretcher = Stretcher::new(rate, SAMPLE_RATE);
+        let mut output = Vec::new();
+        for chunk in input.chunks(chunk_frames * 2) {
+            stretcher.process(chunk, &mut output);
+        }
+        stretcher.finish(&mut output);
+        output
+    }
+
+    /// Time of the first millisecond reaching half the RMS level of the
+    /// end of `samples`.
+    pub(crate) fn onset(samples: &[f32], sample_rate: u32) -> f64 {
+        let frames = sample_rate as usize / 1000;
+        let level = |frame: usize| {
+            let window = &samples[frame * 2..(frame + frames) * 2];
+            window.iter().map(|s| s * s).sum::<f32>().sqrt()
+        };
+        let full = level(samples.len() / 2 - frames * 20);
+        let first = (0..).find(|&frame| level(frame) >= full * 0.5).unwrap();
+        (first + frames / 2) as f64 / sample_rate as f64
+    }
+
+    #[test]
+    fn output_length_follows_rate() {
+        let input = tone(1.0);
+        for rate in [0.5, 0.8, 1.5, 2.0] {
+            let output = stretch(&input, rate, 1000);
+            let expected = (input.len() / 2) as f64 / rate;
+            assert_eq!(output.len() / 2, expected.round() as usize, "{}", rate);
+        }
+    }
+
+    #[test]
+    fn pitch_is_preserved() {
+        for rate in [0.5, 2.0] {
+            let output = stretch(&tone(1.0), rate, 1000);
+            // Rising zero crossings of the left channel over the last half
+            // second of the tone
+            let end = output.len() / 2;
+            let left: Vec<f32> = output[(end - SAMPLE_RATE as usize / 2) * 2..end * 2]
+                .iter()
+                .step_by(2)
+                .copied()
+                .collect();
+            let crossings = left
+                .windows(2)
+                .filter(|w| w[0] < 0.0 && w[1] >= 0.0)
+                .count();
+            assert!(
+                (crossings as i32 - 220).abs() <= 2,
+                "{}: {}",
+                rate,
+                crossings
+            );
+        }
+    }
+
+    /// Range of the error in seconds of an onset stretched to `rate`, as
+    /// measured by `onset`.
+    pub(crate) fn onset_error(rate: f64) -> std::ops::RangeInclusive<f64> {
+        let early = WINDOW_SECONDS / 2.0 * (1.0 / rate - 1.0).max(0.0);
+        -(early + TOLERANCE_SECONDS + 0.001)..=TOLERANCE_SECONDS + 0.001
+    }
+
+    #[test]
+    fn onsets_stay_aligned() {
+        for rate in [0.5, 0.8, 1.5, 2.0] {
+            // Onsets falling on different parts of the windows
+            for shift in 0..4 {
+                let time = 1.0 + shift as f64 * 0.0053;
+                let output = stretch(&tone(time as f32), rate, 1000);
+                let error = onset(&output, SAMPLE_RATE) - time / rate;
+                assert!(onset_error(rate).contains(&error), "{}: {}", rate, error);
+            }
+        }
+    }
+
+    #[test]
+    fn chunking_does_not_change_output() {
+        let input = tone(1.0);
+        for rate in [0.5, 2.0] {
+            let whole = stretch(&input, rate, input.len());
+            assert_eq!(stretch(&input, rate, 777), whole, "{}", rate);
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Checks of a mix timeline against its sounds, run before any decoding.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use super::{Sound, Timestamp, MUSIC_KEY};
+use crate::ffmpeg::{MAX_PLAYBACK_RATE, MIN_PLAYBACK_RATE};
+
+/// Something wrong with the inputs of `mix_audio`. `index` is the position
+/// of the offending timestamp.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum TimelineProblem {
+    InvalidLength {
+        length: f64,
+    },
+    UnknownSound {
+        index: usize,
+        sound: String,
+    },
+    TimeOutOfRange {
+        index: usize,
+        time: f64,
+    },
+    InvalidVolume {
+        index: usize,
+        volume: f32,
+    },
+    InvalidRate {
+        index: usize,
+        rate: f32,
+    },
+    InvalidPan {
+        index: usize,
+        pan: f32,
+    },
+    /// A sound whose key is reserved for the music and its stem.
+    ReservedSound {
+        sound: String,
+    },
+    /// A sound no timestamp refers to. Only a warning; mixing still works.
+    UnusedSound {
+        sound: String,
+    },
+}
+
+impl TimelineProblem {
+    /// Whether the problem prevents mixing.
+    pub fn is_error(&self) -> bool {
+        !matches!(self, Self::UnusedSound { .. })
+    }
+}
+
+impl fmt::Display for TimelineProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLength { length } => write!(f, "invalid length {}", length),
+            Self::UnknownSound { index, sound } => {
+                write!(f, "timestamp {} uses unknown sound {}", index, sound)
+            }
+            Self::TimeOutOfRange { index, time } => {
+                write!(f, "timestamp {} is out of range at {}s", index, time)
+            }
+            Self::InvalidVolume { index, volume } => {
+                write!(f, "timestamp {} has invalid volume {}", index, volume)
+            }
+            Self::InvalidRate { index, rate } => {
+                write!(f, "timestamp {} has invalid rate {}", index, rate)
+            }
+            Self::InvalidPan { index, pan } => {
+                write!(f, "timestamp {} has invalid pan {}", index, pan)
+            }
+            Self::ReservedSound { sound } => {
+                write!(f, "sound key {} is reserved for the music", sound)
+            }
+            Self::UnusedSound { sound } => write!(f, "sound {} is never used", sound),
+        }
+    }
+}
+
+/// Check every timestamp against `sounds` and a timeline of `length`
+/// seconds, listing all problems found.
+pub fn validate_timeline(
+    sounds: &[Sound],
+    timestamps: &[Timestamp],
+    length: f64,
+) -> Vec<TimelineProblem> {
+    let mut problems = Vec::new();
+    if !length.is_finite() || length < 0.0 {
+        problems.push(TimelineProblem::InvalidLength { length });
+    }
+
+    let keys: HashSet<&str> = sounds.iter().map(|sound| sound.key.as_str()).collect();
+    let mut used: HashSet<&str> = HashSet::new();
+    for (index, timestamp) in timestamps.iter().enumerate() {
+        if keys.contains(timestamp.sound.as_str()) {
+            used.insert(&timestamp.sound);
+        } else {
+            problems.push(TimelineProblem::UnknownSound {
+                index,
+                sound: timestamp.sound.clone(),
+            });
+        }
+        if !(timestamp.time >= 0.0 && timestamp.time < length) {
+            problems.push(TimelineProblem::TimeOutOfRange {
+                index,
+                time: timestamp.time,
+            });
+        }
+        if !(timestamp.volume.is_finite() && timestamp.volume >= 0.0) {
+            problems.push(TimelineProblem::InvalidVolume {
+                index,
+                volume: timestamp.volume,
+            });
+        }
+        // Bounded like the playback rate of a render, as a slow rate
+        // stretches the sound in memory by its inverse
+        if !(MIN_PLAYBACK_RATE..=MAX_PLAYBACK_RATE).contains(&(timestamp.rate as f64)) {
+            problems.push(TimelineProblem::InvalidRate {
+                index,
+                rate: timestamp.rate,
+            });
+        }
+        if !(-1.0..=1.0).contains(&timestamp.pan) {
+            problems.push(TimelineProblem::InvalidPan {
+                index,
+                pan: timestamp.pan,
+            });
+        }
+    }
+
+    for sound in sounds {
+        if sound.key == MUSIC_KEY {
+            problems.push(TimelineProblem::ReservedSound {
+                sound: sound.key.clone(),
+            });
+        }
+        if !used.contains(sound.key.as_str()) {
+            problems.push(TimelineProblem::UnusedSound {
+                sound: sound.key.clone(),
+            });
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_is_bounded() {
+        let sounds = vec![Sound::new("a", String::new())];
+        let rates = [
+            1.0,
+            0.25,
+            4.0,
+            1e-6,
+            0.0,
+            -1.0,
+            5.0,
+            f32::NAN,
+            f32::INFINITY,
+        ];
+        let timestamps: Vec<Timestamp> = rates
+            .iter()
+            .map(|&rate| Timestamp {
+                rate,
+                ..Timestamp::new("a", 0.0, 1.0)
+            })
+            .collect();
+        let invalid: Vec<usize> = validate_timeline(&sounds, &timestamps, 1.0)
+            .into_iter()
+            .filter_map(|problem| match problem {
+                TimelineProblem::InvalidRate { index, .. } => Some(index),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(invalid, vec![3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn music_key_is_reserved() {
+        let sounds = vec![
+            Sound::new("tap", String::new()),
+            Sound::new(MUSIC_KEY, String::new()),
+        ];
+        let timestamps = vec![
+            Timestamp::new("tap", 0.0, 1.0),
+            Timestamp::new(MUSIC_KEY, 0.0, 1.0),
+        ];
+        let problems = validate_timeline(&sounds, &timestamps, 1.0);
+        assert!(matches!(
+            problems.as_slice(),
+            [TimelineProblem::ReservedSound { sound }] if sound == MUSIC_KEY
+        ));
+        assert!(problems[0].is_error());
+    }
+}
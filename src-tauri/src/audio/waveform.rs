@@ -0,0 +1,258 @@
+//! Waveform overviews for the timeline and preview UI.
+//!
+//! A file is summarized once into a pyramid of zoom levels: the finest holds
+//! the min, max and mean square of every `BIN_FRAMES` frames of the mono
+//! downmix, and each following level merges pairs of bins of the previous
+//! one. Each bucket of a request is assembled from the coarsest bins that
+//! fit entirely within it, so zooming into a short range costs no more than
+//! the overview and bucket edges stay within a bin of the finest level.
+
+/// Frames summarized by a bin of the finest level.
+const BIN_FRAMES: usize = 128;
+
+/// Values stored per bin: min, max and mean square.
+const BIN_VALUES: usize = 3;
+
+/// Values before the levels in a flattened summary: sample rate, duration
+/// and number of bins of the finest level.
+const HEADER_VALUES: usize = 3;
+
+/// Min, max and RMS of the mono downmix per bucket of a time range.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Waveform {
+    /// Duration of the whole file in seconds.
+    pub duration: f64,
+    pub start: f64,
+    pub end: f64,
+    pub min: Vec<f32>,
+    pub max: Vec<f32>,
+    pub rms: Vec<f32>,
+}
+
+/// Summary of a decoded file as stored in the cache: the header followed by
+/// the bins of every level, finest first.
+pub fn summarize(samples: &[f32], channels: usize, sample_rate: u32) -> Vec<f32> {
+    let channels = channels.max(1);
+    let frames = samples.len() / channels;
+    let mut level: Vec<[f32; BIN_VALUES]> = samples
+        .chunks(BIN_FRAMES * channels)
+        .map(|chunk| {
+            let (mut min, mut max, mut squares) = (f32::MAX, f32::MIN, 0.0);
+            for frame in chunk.chunks_exact(channels) {
+                let value = frame.iter().sum::<f32>() / channels as f32;
+                min = min.min(value);
+                max = max.max(value);
+                squares += value * value;
+            }
+            [min, max, squares / (chunk.len() / channels) as f32]
+        })
+        .collect();
+
+    let mut summary = vec![
+        sample_rate as f32,
+        (frames as f64 / sample_rate as f64) as f32,
+        level.len() as f32,
+    ];
+    loop {
+        summary.extend(level.iter().flatten());
+        if level.len() <= 1 {
+            break;
+        }
+        level = level
+            .chunks(2)
+            .map(|pair| merge(pair.as_flattened()))
+            .collect();
+    }
+    summary
+}
+
+/// Combine consecutive flattened bins into one.
+fn merge(bins: &[f32]) -> [f32; BIN_VALUES] {
+    let count = (bins.len() / BIN_VALUES) as f32;
+    let mut merged = [f32::MAX, f32::MIN, 0.0];
+    for bin in bins.chunks_exact(BIN_VALUES) {
+        merged[0] = merged[0].min(bin[0]);
+        merged[1] = merged[1].max(bin[1]);
+        merged[2] += bin[2] / count;
+    }
+    merged
+}
+
+/// Split a flattened summary into its sample rate, duration and levels.
+fn levels(summary: &[f32]) -> Result<(f64, f64, Vec<&[f32]>), String> {
+    let invalid = || "Invalid waveform summary".to_string();
+    let (header, mut rest) = summary
+        .split_at_checked(HEADER_VALUES)
+        .ok_or_else(invalid)?;
+    let mut bins = header[2] as usize;
+    let mut levels = Vec::new();
+    while bins > 0 {
+        let (level, next) = rest
+            .split_at_checked(bins * BIN_VALUES)
+            .ok_or_else(invalid)?;
+        levels.push(level);
+        rest = next;
+        if bins == 1 {
+            break;
+        }
+        bins = bins.div_ceil(2);
+    }
+    Ok((header[0] as f64, header[1] as f64, levels))
+}
+
+/// Compute `buckets` buckets between `start` and `end` seconds (the whole
+/// file by default) from a summary. Buckets past the end of the audio are
+/// silent.
+pub fn waveform(
+    summary: &[f32],
+    buckets: usize,
+    start: Option<f64>,
+    end: Option<f64>,
+) -> Result<Waveform, String> {
+    let (sample_rate, duration, levels) = levels(summary)?;
+    let start = start.unwrap_or(0.0).max(0.0);
+    let end = end.unwrap_or(duration);
+    if buckets == 0 || end <= start {
+        return Err("The waveform needs at least one bucket and a non-empty range".to_string());
+    }
+
+    let bucket_frames = (end - start) * sample_rate / buckets as f64;
+    let bin_count = levels.first().map_or(0, |bins| bins.len() / BIN_VALUES);
+
+    let mut waveform = Waveform {
+        duration,
+        start,
+        end,
+        min: Vec::with_capacity(buckets),
+        max: Vec::with_capacity(buckets),
+        rms: Vec::with_capacity(buckets),
+    };
+    for i in 0..buckets {
+        // Finest bins whose center lies within the bucket, so that every bin
+        // counts towards exactly one bucket, or the one containing the
+        // bucket when zoomed in beyond the finest level
+        let from = (start * sample_rate + i as f64 * bucket_frames) / BIN_FRAMES as f64;
+        let to = from + bucket_frames / BIN_FRAMES as f64;
+        let first = (from - 0.5).ceil().max(0.0) as usize;
+        let last = ((to - 0.5).ceil().max(0.0) as usize).min(bin_count);
+        let (first, last) = if first < last {
+            (first, last)
+        } else {
+            (from as usize, (from as usize + 1).min(bin_count))
+        };
+        let [min, max, mean_square] = range(&levels, first, last);
+        waveform.min.push(min);
+        waveform.max.push(max);
+        waveform.rms.push(mean_square.sqrt());
+    }
+    Ok(waveform)
+}
+
+/// Min, max and mean square of the finest bins `first..last`, read from
+/// the coarsest bins lying entirely within them. Silent if empty.
+fn range(levels: &[&[f32]], first: usize, last: usize) -> [f32; BIN_VALUES] {
+    if first >= last {
+        return [0.0; BIN_VALUES];
+    }
+    let mut merged = [f32::MAX, f32::MIN, 0.0];
+    let mut bin = first;
+    while bin < last {
+        // Coarser bins are aligned to powers of two of finest bins
+        let mut level = 0;
+        while level + 1 < levels.len()
+            && bin & ((2 << level) - 1) == 0
+            && bin + (2 << level) <= last
+        {
+            level += 1;
+        }
+        let index = (bin >> level) * BIN_VALUES;
+        let values = &levels[level][index..index + BIN_VALUES];
+        let width = (1 << level).min(last - bin);
+        merged[0] = merged[0].min(values[0]);
+        merged[1] = merged[1].max(values[1]);
+        merged[2] += values[2] * width as f32;
+        bin += width;
+    }
+    merged[2] /= (last - first) as f32;
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sample rate at which a bucket of 1/100 s is one finest bin.
+    const SAMPLE_RATE: u32 = BIN_FRAMES as u32 * 100;
+
+    #[test]
+    fn buckets_do_not_spill_across_edges() {
+        let samples: Vec<f32> = (0..SAMPLE_RATE as usize * 2)
+            .map(|i| if i < SAMPLE_RATE as usize { 0.25 } else { -0.5 })
+            .collect();
+        let summary = summarize(&samples, 1, SAMPLE_RATE);
+        for buckets in [2, 4, 10, 200] {
+            let waveform = waveform(&summary, buckets, None, None).unwrap();
+            for i in 0..buckets {
+                let expected = if i < buckets / 2 { 0.25 } else { -0.5 };
+                assert_eq!(waveform.min[i], expected, "{} of {}", i, buckets);
+                assert_eq!(waveform.max[i], expected, "{} of {}", i, buckets);
+                assert!((waveform.rms[i] - expected.abs()).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn zoom_levels_agree() {
+        // A quiet tone with a click in the middle of the 500th bin
+        let click = 500 * BIN_FRAMES + BIN_FRAMES / 2;
+        let samples: Vec<f32> = (0..SAMPLE_RATE as usize * 10)
+            .map(|i| {
+                if i == click {
+                    0.9
+                } else {
+                    0.1 * (i as f32 * 0.05).sin()
+                }
+            })
+            .collect();
+        let summary = summarize(&samples, 1, SAMPLE_RATE);
+        let mean_square = samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32;
+
+        for (buckets, start, end) in [
+            (1, 0.0, 10.0),
+            (10, 0.0, 10.0),
+            (100, 0.0, 10.0),
+            (1000, 0.0, 10.0),
+            (20, 4.9, 5.1),
+            (7, 4.93, 5.0),
+            (3, 4.0, 7.0),
+        ] {
+            let waveform = waveform(&summary, buckets, Some(start), Some(end)).unwrap();
+            let bucket_frames = (end - start) * SAMPLE_RATE as f64 / buckets as f64;
+            let clicked = ((click as f64 - start * SAMPLE_RATE as f64) / bucket_frames) as usize;
+            for i in 0..buckets {
+                assert_eq!(
+                    waveform.max[i] == 0.9,
+                    i == clicked,
+                    "{} of {} from {} to {}",
+                    i,
+                    buckets,
+                    start,
+                    end
+                );
+            }
+            if start == 0.0 {
+                let total = waveform.rms.iter().map(|rms| rms * rms).sum::<f32>() / buckets as f32;
+                assert!((total - mean_square).abs() < 1e-4 * mean_square);
+            }
+        }
+    }
+
+    #[test]
+    fn buckets_past_the_end_are_silent() {
+        let summary = summarize(&[0.5; 1000], 1, SAMPLE_RATE);
+        let waveform = waveform(&summary, 4, Some(0.0), Some(1.0)).unwrap();
+        assert_eq!(waveform.max[0], 0.5);
+        assert_eq!(waveform.max[3], 0.0);
+    }
+}
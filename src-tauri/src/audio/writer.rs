@@ -0,0 +1,387 @@
+//! Output of mixed audio. WAV and FLAC are written natively; any other
+//! extension is piped through FFmpeg for a lossy encode.
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::process::{Child, ChildStdin, Stdio};
+
+use flacenc::bitsink::MemSink;
+use flacenc::component::{BitRepr, StreamInfo};
+use flacenc::error::Verify;
+use flacenc::source::{Context, Fill, FrameBuf};
+use hound::{SampleFormat, WavSpec};
+
+/// Frames per FLAC frame.
+const FLAC_BLOCK_SIZE: usize = 4096;
+
+/// Byte offset of the STREAMINFO body in a FLAC file (after `fLaC` and the
+/// metadata block header).
+const FLAC_STREAMINFO_OFFSET: u64 = 8;
+
+/// Writes interleaved stereo `f32` blocks to an audio file.
+pub enum OutputWriter {
+    Wav(hound::WavWriter<BufWriter<File>>),
+    Flac(Box<FlacWriter>),
+    Ffmpeg {
+        proc: Child,
+        writer: BufWriter<ChildStdin>,
+    },
+}
+
+impl OutputWriter {
+    /// Open `output`, choosing the encoder from its extension. `bit_depth`
+    /// defaults to 32-bit float for WAV and 24-bit for FLAC, and is ignored
+    /// for lossy formats.
+    pub fn create(output: &str, sample_rate: u32, bit_depth: Option<u16>) -> Result<Self, String> {
+        let extension = std::path::Path::new(output)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        match extension.as_str() {
+            "wav" => {
+                let spec = match bit_depth.unwrap_or(32) {
+                    bits @ (16 | 24) => WavSpec {
+                        channels: 2,
+                        sample_rate,
+                        bits_per_sample: bits,
+                        sample_format: SampleFormat::Int,
+                    },
+                    32 => WavSpec {
+                        channels: 2,
+                        sample_rate,
+                        bits_per_sample: 32,
+                        sample_format: SampleFormat::Float,
+                    },
+                    bits => return Err(format!("Unsupported WAV bit depth: {}", bits)),
+                };
+                let writer = hound::WavWriter::create(output, spec)
+                    .map_err(|e| format!("Error creating {}: {}", output, e))?;
+                Ok(Self::Wav(writer))
+            }
+            "flac" => {
+                let bits = bit_depth.unwrap_or(24);
+                if bits != 16 && bits != 24 {
+                    return Err(format!("Unsupported FLAC bit depth: {}", bits));
+                }
+                Ok(Self::Flac(Box::new(FlacWriter::create(
+                    output,
+                    sample_rate,
+                    bits as usize,
+                )?)))
+            }
+            _ => {
+                let mut proc = crate::ffmpeg::command()
+                    .args(format!("-y -f f32le -ar {} -ac 2 -i -", sample_rate).split_whitespace())
+                    .arg(output)
+                    .stdin(Stdio::piped())
+                    .stderr(Stdio::inherit())
+                    .spawn()
+                    .map_err(|e| e.to_string())?;
+                let writer = BufWriter::new(proc.stdin.take().unwrap());
+                Ok(Self::Ffmpeg { proc, writer })
+            }
+        }
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        match self {
+            Self::Wav(writer) => {
+                let spec = writer.spec();
+                for &sample in samples {
+                    let result = match (spec.sample_format, spec.bits_per_sample) {
+                        (SampleFormat::Float, _) => writer.write_sample(sample),
+                        (_, 16) => writer.write_sample(quantize(sample, 16) as i16),
+                        (_, bits) => writer.write_sample(quantize(sample, bits)),
+                    };
+                    result.map_err(|e| e.to_string())?;
+                }
+                Ok(())
+            }
+            Self::Flac(writer) => writer.write(samples),
+            Self::Ffmpeg { writer, .. } => {
+                for sample in samples {
+                    writer
+                        .write_all(&sample.to_le_bytes())
+                        .map_err(|e| e.to_string())?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        match self {
+            Self::Wav(writer) => writer.finalize().map_err(|e| e.to_string()),
+            Self::Flac(writer) => writer.finish(),
+            Self::Ffmpeg {
+                mut proc,
+                mut writer,
+            } => {
+                writer.flush().map_err(|e| e.to_string())?;
+                drop(writer);
+                if proc.wait().map_err(|e| e.to_string())?.success() {
+                    Ok(())
+                } else {
+                    Err("FFmpeg process failed".to_string())
+                }
+            }
+        }
+    }
+}
+
+/// Convert a float sample to a signed integer of `bits` bits, clipping at
+/// full scale.
+fn quantize(sample: f32, bits: u16) -> i32 {
+    let max = ((1i64 << (bits - 1)) - 1) as f32;
+    (sample.clamp(-1.0, 1.0) * max).round() as i32
+}
+
+/// Serialize a FLAC component.
+fn to_bytes<T: BitRepr>(component: &T) -> Result<Vec<u8>, String> {
+    let mut sink = MemSink::<u8>::new();
+    component.write(&mut sink).map_err(|e| e.to_string())?;
+    Ok(sink.into_inner())
+}
+
+/// Streams FLAC frames to disk as they are encoded, rewriting STREAMINFO
+/// once the totals are known.
+pub struct FlacWriter {
+    file: BufWriter<File>,
+    config: flacenc::error::Verified<flacenc::config::Encoder>,
+    stream_info: StreamInfo,
+    framebuf: FrameBuf,
+    context: Context,
+    bits: u16,
+    /// Interleaved samples not yet forming a full FLAC frame.
+    pending: Vec<i32>,
+    frame_number: usize,
+}
+
+impl FlacWriter {
+    fn create(output: &str, sample_rate: u32, bits: usize) -> Result<Self, String> {
+        let config = flacenc::config::Encoder::default()
+            .into_verified()
+            .map_err(|(_, e)| e.to_string())?;
+        let stream_info =
+            StreamInfo::new(sample_rate as usize, 2, bits).map_err(|e| e.to_string())?;
+        let framebuf = FrameBuf::with_size(2, FLAC_BLOCK_SIZE).map_err(|e| e.to_string())?;
+
+        let mut file = BufWriter::new(
+            File::create(output).map_err(|e| format!("Error creating {}: {}", output, e))?,
+        );
+        // Marker and the (last) STREAMINFO block header, followed by a
+        // placeholder body
+        file.write_all(b"fLaC").map_err(|e| e.to_string())?;
+        file.write_all(&[0x80, 0x00, 0x00, 34])
+            .map_err(|e| e.to_string())?;
+        file.write_all(&to_bytes(&stream_info)?)
+            .map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            file,
+            config,
+            stream_info,
+            framebuf,
+            context: Context::new(bits, 2),
+            bits: bits as u16,
+            pending: Vec::with_capacity(FLAC_BLOCK_SIZE * 2),
+            frame_number: 0,
+        })
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        for &sample in samples {
+            self.pending.push(quantize(sample, self.bits));
+            if self.pending.len() == FLAC_BLOCK_SIZE * 2 {
+                self.encode_pending()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_pending(&mut self) -> Result<(), String> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.framebuf
+            .fill_interleaved(&self.pending)
+            .map_err(|e| e.to_string())?;
+        self.context
+            .fill_interleaved(&self.pending)
+            .map_err(|e| e.to_string())?;
+        let frame = flacenc::encode_fixed_size_frame(
+            &self.config,
+            &self.framebuf,
+            self.frame_number,
+            &self.stream_info,
+        )
+        .map_err(|e| e.to_string())?;
+        self.stream_info.update_frame_info(&frame);
+        self.file
+            .write_all(&to_bytes(&frame)?)
+            .map_err(|e| e.to_string())?;
+        self.frame_number += 1;
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), String> {
+        self.encode_pending()?;
+        // The minimum block size excludes the last, usually shorter, frame;
+        // strict decoders take differing sizes for a variable-size stream
+        self.stream_info
+            .set_block_sizes(FLAC_BLOCK_SIZE, FLAC_BLOCK_SIZE)
+            .map_err(|e| e.to_string())?;
+        self.stream_info.set_md5_digest(&self.context.md5_digest());
+        self.file
+            .seek(SeekFrom::Start(FLAC_STREAMINFO_OFFSET))
+            .map_err(|e| e.to_string())?;
+        self.file
+            .write_all(&to_bytes(&self.stream_info)?)
+            .map_err(|e| e.to_string())?;
+        self.file.flush().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::audio::SampleBuffer;
+    use symphonia::core::codecs::DecoderOptions;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::probe::Hint;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    /// Stereo test signal of `frames` frames, clipping at its peaks.
+    fn signal(frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let value = 1.2 * (i as f32 * 0.01).sin();
+                [value, -0.5 * value]
+            })
+            .collect()
+    }
+
+    /// Write `samples` to a temporary file with `extension` in blocks of an
+    /// odd size, returning its path.
+    fn write(name: &str, extension: &str, bit_depth: Option<u16>, samples: &[f32]) -> String {
+        let path = std::env::temp_dir().join(format!("phizone-writer-{}.{}", name, extension));
+        let path = path.to_string_lossy().into_owned();
+        let mut writer = OutputWriter::create(&path, SAMPLE_RATE, bit_depth).unwrap();
+        for block in samples.chunks(1001 * 2) {
+            writer.write(block).unwrap();
+        }
+        writer.finish().unwrap();
+        path
+    }
+
+    /// Samples of a FLAC file at full 32-bit scale, and whether their MD5
+    /// matches STREAMINFO.
+    fn decode_flac(path: &str) -> (Vec<i32>, Option<bool>) {
+        let file = File::open(path).unwrap();
+        let source = MediaSourceStream::new(Box::new(file), Default::default());
+        let mut format = symphonia::default::get_probe()
+            .format(
+                &Hint::new(),
+                source,
+                &Default::default(),
+                &Default::default(),
+            )
+            .unwrap()
+            .format;
+        let track = format.default_track().unwrap();
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions { verify: true })
+            .unwrap();
+        let mut samples = Vec::new();
+        while let Ok(packet) = format.next_packet() {
+            let decoded = decoder.decode(&packet).unwrap();
+            let mut buffer = SampleBuffer::<i32>::new(decoded.capacity() as u64, *decoded.spec());
+            buffer.copy_interleaved_ref(decoded);
+            samples.extend_from_slice(buffer.samples());
+        }
+        (samples, decoder.finalize().verify_ok)
+    }
+
+    #[test]
+    fn flac_round_trips() {
+        // Two full FLAC frames and a partial one
+        let frames = FLAC_BLOCK_SIZE * 2 + 1234;
+        let samples = signal(frames);
+        for bits in [16, 24] {
+            let path = write(
+                &format!("round-trip-{}", bits),
+                "flac",
+                Some(bits),
+                &samples,
+            );
+            let bytes = std::fs::read(&path).unwrap();
+            let (decoded, verified) = decode_flac(&path);
+            std::fs::remove_file(&path).unwrap();
+
+            // STREAMINFO: block sizes, then sample rate, channels, bit depth
+            // and total frames packed from byte 18 on
+            let info = &bytes[FLAC_STREAMINFO_OFFSET as usize..][..34];
+            let block_size = FLAC_BLOCK_SIZE as u16;
+            assert_eq!(u16::from_be_bytes([info[0], info[1]]), block_size);
+            assert_eq!(u16::from_be_bytes([info[2], info[3]]), block_size);
+            let packed = u64::from_be_bytes(info[10..18].try_into().unwrap());
+            assert_eq!(packed >> 44, SAMPLE_RATE as u64);
+            assert_eq!((packed >> 41 & 0x7) + 1, 2);
+            assert_eq!((packed >> 36 & 0x1F) + 1, bits as u64);
+            assert_eq!(packed & 0xF_FFFF_FFFF, frames as u64);
+            assert_eq!(verified, Some(true));
+
+            let expected: Vec<i32> = samples.iter().map(|&s| quantize(s, bits)).collect();
+            let decoded: Vec<i32> = decoded.iter().map(|&s| s >> (32 - bits)).collect();
+            assert_eq!(decoded, expected, "{} bits", bits);
+        }
+    }
+
+    #[test]
+    fn wav_round_trips() {
+        let samples = signal(5000);
+        for bits in [16, 24, 32] {
+            let path = write(&format!("round-trip-{}", bits), "wav", Some(bits), &samples);
+            let mut reader = hound::WavReader::open(&path).unwrap();
+            let spec = reader.spec();
+            assert_eq!((spec.channels, spec.sample_rate), (2, SAMPLE_RATE));
+            assert_eq!(spec.bits_per_sample, bits);
+            if bits == 32 {
+                let decoded: Vec<f32> = reader.samples().map(Result::unwrap).collect();
+                assert_eq!(decoded, samples);
+            } else {
+                let decoded: Vec<i32> = reader.samples().map(Result::unwrap).collect();
+                let expected: Vec<i32> = samples.iter().map(|&s| quantize(s, bits)).collect();
+                assert_eq!(decoded, expected, "{} bits", bits);
+            }
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn unsupported_bit_depths_are_rejected() {
+        let path = std::env::temp_dir().join("phizone-writer-unsupported.flac");
+        let path = path.to_string_lossy();
+        assert!(OutputWriter::create(&path, SAMPLE_RATE, Some(32)).is_err());
+        assert!(
+            OutputWriter::create(&path.replace(".flac", ".wav"), SAMPLE_RATE, Some(8)).is_err()
+        );
+    }
+
+    #[test]
+    #[ignore] // Needs FFmpeg
+    fn ffmpeg_round_trips() {
+        let samples = signal(5000);
+        let path = write("round-trip", "aiff", None, &samples);
+        let decoded = super::super::decode::decode("aiff", std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((decoded.channels, decoded.sample_rate), (2, SAMPLE_RATE));
+        assert_eq!(decoded.samples.len(), samples.len());
+        for (decoded, expected) in decoded.samples.iter().zip(&samples) {
+            assert!((decoded - expected.clamp(-1.0, 1.0)).abs() < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! Binary uploads received over the WebSocket server. Commands refer to an
+//! uploaded blob with a `blob:<id>` string in place of base64 data or a
+//! file path.
+//!
+//! An upload is started with `begin_blob`, declaring its total size, and
+//! its data then follows in tagged binary messages on the same IPC
+//! connection. A blob can only be used once all of it has arrived, and
+//! starting it again discards what was received before.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+
+/// Magic starting a binary WS message that carries blob data. It is
+/// followed by a one-byte id length, the UTF-8 id and the payload.
+pub const BLOB_MAGIC: &[u8; 4] = b"BLOB";
+
+/// Prefix of string arguments referring to a blob.
+pub const BLOB_PREFIX: &str = "blob:";
+
+/// Prefix of the names of temporary blob files.
+const TEMP_PREFIX: &str = "phizone-blob-";
+
+/// Size above which a blob is moved from memory to a temporary file.
+const MEMORY_LIMIT: u64 = 16 << 20;
+
+enum Data {
+    Memory(Vec<u8>),
+    File(PathBuf),
+}
+
+struct Blob {
+    data: Data,
+    /// Bytes received so far and declared in total.
+    received: u64,
+    size: u64,
+}
+
+static BLOBS: LazyLock<Mutex<HashMap<String, Blob>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Split a tagged binary message into blob id and payload, or `None` if it
+/// is malformed.
+pub fn parse_message(data: &[u8]) -> Option<(&str, &[u8])> {
+    let rest = data.strip_prefix(BLOB_MAGIC)?;
+    let (&id_len, rest) = rest.split_first()?;
+    if rest.len() < id_len as usize {
+        return None;
+    }
+    let (id, payload) = rest.split_at(id_len as usize);
+    Some((std::str::from_utf8(id).ok()?, payload))
+}
+
+/// Temporary file of blob `id`, named by a hash so that any id is safe.
+fn temp_path(id: &str) -> PathBuf {
+    let hash = blake3::hash(id.as_bytes()).to_hex();
+    std::env::temp_dir().join(format!("{}{}", TEMP_PREFIX, &hash[..16]))
+}
+
+/// Delete the temporary file of a dropped blob, if it has one.
+fn remove(id: &str, blob: Blob) -> Result<(), String> {
+    match blob.data {
+        Data::File(path) => {
+            std::fs::remove_file(&path).map_err(|e| format!("Failed to remove blob {}: {}", id, e))
+        }
+        Data::Memory(_) => Ok(()),
+    }
+}
+
+/// Start the upload of blob `id` of `size` bytes, replacing any blob with
+/// the same id.
+pub fn begin(id: &str, size: u64) -> Result<(), String> {
+    let blob = Blob {
+        data: Data::Memory(Vec::new()),
+        received: 0,
+        size,
+    };
+    let previous = BLOBS.lock().unwrap().insert(id.to_string(), blob);
+    match previous {
+        Some(previous) => remove(id, previous),
+        None => Ok(()),
+    }
+}
+
+/// Append `data` to the upload of blob `id`. Returns the number of bytes
+/// received so far.
+pub fn append(id: &str, data: &[u8]) -> Result<u64, String> {
+    let mut blobs = BLOBS.lock().unwrap();
+    let blob = blobs
+        .get_mut(id)
+        .ok_or_else(|| format!("Blob {} was not started", id))?;
+    let received = blob.received + data.len() as u64;
+    if received > blob.size {
+        return Err(format!(
+            "Blob {} is {} bytes, but {} were sent",
+            id, blob.size, received
+        ));
+    }
+
+    if let Data::Memory(bytes) = &mut blob.data {
+        if received <= MEMORY_LIMIT {
+            bytes.extend_from_slice(data);
+            blob.received = received;
+            return Ok(received);
+        }
+        let path = temp_path(id);
+        std::fs::write(&path, bytes).map_err(|e| format!("Failed to write blob {}: {}", id, e))?;
+        blob.data = Data::File(path);
+    }
+
+    let Data::File(path) = &blob.data else {
+        unreachable!()
+    };
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open blob {}: {}", id, e))?;
+    file.write_all(data)
+        .map_err(|e| format!("Failed to write blob {}: {}", id, e))?;
+    blob.received = received;
+    Ok(received)
+}
+
+/// Blob `id`, if all of it has been received.
+fn complete<'a>(blobs: &'a mut HashMap<String, Blob>, id: &str) -> Result<&'a mut Blob, String> {
+    let blob = blobs
+        .get_mut(id)
+        .ok_or_else(|| format!("Blob {} not found", id))?;
+    if blob.received < blob.size {
+        return Err(format!(
+            "Blob {} is incomplete: {} of {} bytes received",
+            id, blob.received, blob.size
+        ));
+    }
+    Ok(blob)
+}
+
+/// Contents of blob `id`.
+pub fn read(id: &str) -> Result<Vec<u8>, String> {
+    let mut blobs = BLOBS.lock().unwrap();
+    match &complete(&mut blobs, id)?.data {
+        Data::Memory(bytes) => Ok(bytes.clone()),
+        Data::File(path) => {
+            std::fs::read(path).map_err(|e| format!("Failed to read blob {}: {}", id, e))
+        }
+    }
+}
+
+/// Path of a file holding blob `id`, moving the blob to a temporary file
+/// if it is still in memory.
+pub fn path(id: &str) -> Result<PathBuf, String> {
+    let mut blobs = BLOBS.lock().unwrap();
+    let blob = complete(&mut blobs, id)?;
+    if let Data::Memory(bytes) = &blob.data {
+        let path = temp_path(id);
+        std::fs::write(&path, bytes).map_err(|e| format!("Failed to write blob {}: {}", id, e))?;
+        blob.data = Data::File(path);
+    }
+    let Data::File(path) = &blob.data else {
+        unreachable!()
+    };
+    Ok(path.clone())
+}
+
+/// Resolve a file path argument: `blob:<id>` becomes the path of the blob's
+/// file, anything else is returned unchanged.
+pub fn resolve_path(arg: String) -> Result<String, String> {
+    match arg.strip_prefix(BLOB_PREFIX) {
+        Some(id) => Ok(path(id)?.to_string_lossy().into_owned()),
+        None => Ok(arg),
+    }
+}
+
+/// Drop blob `id`, deleting its temporary file if it has one.
+pub fn release(id: &str) -> Result<(), String> {
+    let blob = BLOBS.lock().unwrap().remove(id);
+    match blob {
+        Some(blob) => remove(id, blob),
+        None => Err(format!("Blob {} not found", id)),
+    }
+}
+
+/// Drop every blob, as the app exits.
+pub fn release_all() {
+    let blobs = std::mem::take(&mut *BLOBS.lock().unwrap());
+    for (id, blob) in blobs {
+        if let Err(e) = remove(&id, blob) {
+            eprintln!("[TAURI] {}", e);
+        }
+    }
+}
+
+/// Delete temporary blob files left behind by a previous run that did not
+/// exit cleanly.
+pub fn remove_stale() {
+    let Ok(entries) = std::fs::read_dir(std::env::temp_dir()) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().starts_with(TEMP_PREFIX) {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incomplete_blobs_cannot_be_used() {
+        begin("incomplete", 6).unwrap();
+        append("incomplete", b"abc").unwrap();
+        assert!(read("incomplete").is_err());
+        assert!(path("incomplete").is_err());
+        assert_eq!(append("incomplete", b"def"), Ok(6));
+        assert_eq!(read("incomplete").unwrap(), b"abcdef");
+        assert!(append("incomplete", b"g").is_err());
+        release("incomplete").unwrap();
+    }
+
+    #[test]
+    fn restarting_discards_previous_data() {
+        begin("restarted", 3).unwrap();
+        append("restarted", b"old").unwrap();
+        begin("restarted", 3).unwrap();
+        append("restarted", b"new").unwrap();
+        assert_eq!(read("restarted").unwrap(), b"new");
+        release("restarted").unwrap();
+        assert!(append("restarted", b"new").is_err());
+    }
+
+    #[test]
+    fn large_blobs_move_to_a_file() {
+        let chunk = vec![7; MEMORY_LIMIT as usize / 2 + 1];
+        begin("large", chunk.len() as u64 * 2).unwrap();
+        append("large", &chunk).unwrap();
+        append("large", &chunk).unwrap();
+        let path = path("large").unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            chunk.len() as u64 * 2
+        );
+        release("large").unwrap();
+        assert!(!path.exists());
+    }
+}
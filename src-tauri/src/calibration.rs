@@ -0,0 +1,314 @@
+//! A/V sync calibration clips: white flashes on black with beeps at known
+//! times, for measuring the display and audio latency of capture setups.
+//!
+//! The frames are encoded with the same FFmpeg settings as a render, but in
+//! a process of their own so that a render can run meanwhile. The beeps are
+//! mixed by `mix_audio` and both are muxed by `combine_streams`, so the clip
+//! goes through the same path as a render. A JSON file next to the output
+//! lists the expected event times.
+
+use std::io::{Cursor, Write};
+use std::path::Path;
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use hound::{SampleFormat, WavSpec};
+use tauri::{AppHandle, Emitter};
+
+use crate::{audio, ffmpeg};
+
+/// Sample rate of the synthesized beep.
+const BEEP_SAMPLE_RATE: u32 = 48000;
+
+/// Fade in seconds at both ends of the beep, short enough to keep its
+/// onset sharp while avoiding clicks.
+const BEEP_RAMP: f64 = 0.001;
+
+/// Sound key of the beep on the mix timeline.
+const BEEP_KEY: &str = "beep";
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CalibrationOptions {
+    pub resolution: String,
+    pub frame_rate: u32,
+    /// Length of the clip in seconds.
+    pub duration: f64,
+    /// Time of the first event in seconds.
+    pub start: f64,
+    /// Seconds between two events.
+    pub interval: f64,
+    /// How long a flash stays on screen in seconds, at least one frame.
+    pub flash_duration: f64,
+    pub beep_duration: f64,
+    /// Pitch of the beep in Hz.
+    pub beep_frequency: f64,
+    pub beep_volume: f32,
+    pub codec: String,
+    pub bitrate: String,
+    pub audio_bitrate: String,
+}
+
+impl Default for CalibrationOptions {
+    fn default() -> Self {
+        Self {
+            resolution: "1280x720".to_string(),
+            frame_rate: 60,
+            duration: 10.0,
+            start: 1.0,
+            interval: 1.0,
+            flash_duration: 0.05,
+            beep_duration: 0.05,
+            beep_frequency: 1000.0,
+            beep_volume: 0.8,
+            codec: "libx264".to_string(),
+            bitrate: "8M".to_string(),
+            audio_bitrate: "192k".to_string(),
+        }
+    }
+}
+
+/// A flash and a beep, both starting at `time`.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CalibrationEvent {
+    pub index: usize,
+    /// First frame of the flash.
+    pub frame: u64,
+    /// Start of the flash and the beep in seconds; the frame time, so that
+    /// both line up exactly.
+    pub time: f64,
+}
+
+/// Contents of the metadata file written next to the clip.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CalibrationMetadata {
+    pub video: String,
+    pub resolution: String,
+    pub frame_rate: u32,
+    pub duration: f64,
+    pub flash_frames: u64,
+    pub beep_duration: f64,
+    pub beep_frequency: f64,
+    pub events: Vec<CalibrationEvent>,
+}
+
+/// Path of the metadata file of the clip at `output`.
+fn metadata_path(output: &str) -> String {
+    Path::new(output)
+        .with_extension("calibration.json")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Path of an intermediate file of the clip at `output` in the temporary
+/// directory.
+fn temp_path(output: &str, extension: &str) -> String {
+    let hash = blake3::hash(output.as_bytes()).to_hex();
+    std::env::temp_dir()
+        .join(format!("phizone-calibration-{}.{}", &hash[..16], extension))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Events at every `interval` from `start` within the clip, snapped to
+/// frames.
+fn events(options: &CalibrationOptions) -> Vec<CalibrationEvent> {
+    let frame_rate = options.frame_rate as f64;
+    let total_frames = (options.duration * frame_rate).ceil() as u64;
+    (0..)
+        .map(|index| options.start + index as f64 * options.interval)
+        .map(|time| (time * frame_rate).round() as u64)
+        .take_while(|&frame| frame < total_frames)
+        .enumerate()
+        .map(|(index, frame)| CalibrationEvent {
+            index,
+            frame,
+            time: frame as f64 / frame_rate,
+        })
+        .collect()
+}
+
+/// A sine beep as a base64 WAV data URL, which `mix_audio` takes as sound
+/// data.
+fn beep_data(frequency: f64, duration: f64) -> Result<String, String> {
+    let spec = WavSpec {
+        channels: 1,
+        sample_rate: BEEP_SAMPLE_RATE,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let mut bytes = Cursor::new(Vec::new());
+    let mut writer = hound::WavWriter::new(&mut bytes, spec).map_err(|e| e.to_string())?;
+    let samples = (duration * BEEP_SAMPLE_RATE as f64).round() as usize;
+    let ramp = BEEP_RAMP * BEEP_SAMPLE_RATE as f64;
+    for i in 0..samples {
+        let envelope = (i as f64 / ramp).min((samples - i) as f64 / ramp).min(1.0);
+        let phase = 2.0 * std::f64::consts::PI * frequency * i as f64 / BEEP_SAMPLE_RATE as f64;
+        writer
+            .write_sample((phase.sin() * envelope) as f32)
+            .map_err(|e| e.to_string())?;
+    }
+    writer.finalize().map_err(|e| e.to_string())?;
+    Ok(format!(
+        "data:audio/wav;base64,{}",
+        STANDARD.encode(bytes.into_inner())
+    ))
+}
+
+/// Encode the frames of the clip to `path` with an FFmpeg process of its
+/// own, leaving the one of a render in progress alone.
+fn render_video(
+    path: &str,
+    options: &CalibrationOptions,
+    width: usize,
+    height: usize,
+    metadata: &CalibrationMetadata,
+) -> Result<(), String> {
+    let mut process = ffmpeg::spawn_video_encoder(
+        path,
+        &options.resolution,
+        options.frame_rate,
+        &options.codec,
+        &options.bitrate,
+    )?;
+    let mut stdin = process.stdin.take().ok_or("FFmpeg stdin not available")?;
+
+    let black = vec![0u8; width * height * 3];
+    let white = vec![255u8; width * height * 3];
+    let total_frames = (options.duration * options.frame_rate as f64).ceil() as u64;
+    let mut events = metadata.events.iter().peekable();
+    let mut written = Ok(());
+    for frame in 0..total_frames {
+        while events
+            .peek()
+            .is_some_and(|event| event.frame + metadata.flash_frames <= frame)
+        {
+            events.next();
+        }
+        let flash = events.peek().is_some_and(|event| event.frame <= frame);
+        written = stdin
+            .write_all(if flash { &white } else { &black })
+            .map_err(|e| format!("Error writing to FFmpeg: {}", e));
+        if written.is_err() {
+            break;
+        }
+    }
+
+    // Closing stdin ends the input
+    drop(stdin);
+    let status = process
+        .wait()
+        .map_err(|e| format!("Error waiting for FFmpeg process: {}", e))?;
+    written?;
+    if !status.success() {
+        return Err(format!("FFmpeg process failed with status: {}", status));
+    }
+    Ok(())
+}
+
+/// Generate a calibration clip at `output`. Frames and beeps are produced
+/// and muxed in the background, emitting `calibration-video-finished` with
+/// the metadata or `calibration-video-failed`. The intermediate files are
+/// removed either way. Returns the path of the metadata file.
+pub fn generate_calibration_video(
+    app: AppHandle,
+    output: String,
+    options: CalibrationOptions,
+) -> Result<String, String> {
+    let (width, height) = options
+        .resolution
+        .split_once('x')
+        .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+        .ok_or_else(|| format!("Invalid resolution: {}", options.resolution))?;
+    if options.frame_rate == 0 || options.duration <= 0.0 || options.interval <= 0.0 {
+        return Err("Frame rate, duration and interval must be positive".to_string());
+    }
+
+    let metadata = CalibrationMetadata {
+        video: output.clone(),
+        resolution: options.resolution.clone(),
+        frame_rate: options.frame_rate,
+        duration: options.duration,
+        flash_frames: ((options.flash_duration * options.frame_rate as f64).round() as u64).max(1),
+        beep_duration: options.beep_duration,
+        beep_frequency: options.beep_frequency,
+        events: events(&options),
+    };
+    let metadata_path = metadata_path(&output);
+
+    std::thread::spawn({
+        let metadata_path = metadata_path.clone();
+        move || {
+            let video_path = temp_path(&output, "mp4");
+            let mix_path = temp_path(&output, "wav");
+            let result = (|| -> Result<(), String> {
+                println!("[TAURI] Generating calibration video...");
+                render_video(&video_path, &options, width, height, &metadata)?;
+
+                let sounds = vec![audio::Sound::new(
+                    BEEP_KEY,
+                    beep_data(options.beep_frequency, options.beep_duration)?,
+                )];
+                let timestamps: Vec<audio::Timestamp> = metadata
+                    .events
+                    .iter()
+                    .map(|event| audio::Timestamp::new(BEEP_KEY, event.time, options.beep_volume))
+                    .collect();
+                audio::mix_audio_sync(
+                    &app,
+                    &sounds,
+                    &timestamps,
+                    options.duration,
+                    &mix_path,
+                    &audio::MixOptions::default(),
+                )?;
+
+                // Without hitsounds, the mix is taken as the final audio
+                ffmpeg::combine_streams_sync(
+                    &app,
+                    video_path.clone(),
+                    mix_path.clone(),
+                    String::new(),
+                    1.0,
+                    &options.audio_bitrate,
+                    &ffmpeg::AudioTiming::default(),
+                    &ffmpeg::OutputFormat::default(),
+                    None,
+                    Some(&options.codec),
+                    &output,
+                )?;
+
+                std::fs::write(
+                    &metadata_path,
+                    serde_json::to_string_pretty(&metadata).unwrap(),
+                )
+                .map_err(|e| format!("Failed to write {}: {}", metadata_path, e))
+            })();
+
+            // Either file may not have been written
+            let _ = std::fs::remove_file(&video_path);
+            let _ = std::fs::remove_file(&mix_path);
+
+            match result {
+                Ok(()) => {
+                    app.emit("calibration-video-finished", &metadata).unwrap();
+                    crate::ws_server::broadcast_event(
+                        "calibration-video-finished",
+                        serde_json::to_value(&metadata).unwrap(),
+                    );
+                }
+                Err(e) => {
+                    eprintln!("[TAURI] Calibration video generation failed: {}", e);
+                    app.emit("calibration-video-failed", &e).unwrap();
+                    crate::ws_server::broadcast_event(
+                        "calibration-video-failed",
+                        serde_json::json!(e),
+                    );
+                }
+            }
+        }
+    });
+
+    Ok(metadata_path)
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 #[cfg(unix)]
 use std::fs;
 use std::io::Write;
@@ -13,6 +14,13 @@ use crate::send_webhook_notification;
 static FFMPEG_CMD: LazyLock<Mutex<String>> = LazyLock::new(|| Mutex::new("ffmpeg".to_string()));
 static FFMPEG_STDIN: LazyLock<Mutex<Option<ChildStdin>>> = LazyLock::new(|| Mutex::new(None));
 static FFMPEG_PROCESS: LazyLock<Mutex<Option<Child>>> = LazyLock::new(|| Mutex::new(None));
+/// Video encoder passed to the last `setup_video_process` call.
+static VIDEO_ENCODER: LazyLock<Mutex<Option<String>>> = LazyLock::new(|| Mutex::new(None));
+
+/// Command running the configured FFmpeg executable.
+pub fn command() -> std::process::Command {
+    cmd_hidden(&*FFMPEG_CMD.lock().unwrap())
+}
 
 fn get_report_interval() -> u32 {
     match std::env::var("REPORT_INTERVAL") {
@@ -128,58 +136,878 @@ pub fn get_encoders() -> Result<Vec<Encoder>, String> {
     Ok(encoders)
 }
 
-pub fn convert_audio(app: AppHandle, input: String, output: String) -> Result<(), String> {
-    std::thread::spawn({
-        let app = app.clone();
-        move || {
-            let detect_cmd = cmd_hidden(&*FFMPEG_CMD.lock().unwrap())
-                .args(format!("-i {} -af volumedetect -f null -", input).split_whitespace())
-                .output();
-
-            let mut gain = 0.0;
-            if let Ok(output_res) = detect_cmd {
-                let stderr = String::from_utf8_lossy(&output_res.stderr);
-                for line in stderr.lines() {
-                    if let Some(idx) = line.find("max_volume:") {
-                        let vol_str = line[idx + 11..].trim();
-                        if let Some(db_idx) = vol_str.find(" dB") {
-                            if let Ok(vol) = vol_str[..db_idx].trim().parse::<f32>() {
-                                gain = -vol;
-                            }
-                        }
-                    }
-                }
+/// Levels and format of an audio file as measured by `convert_audio`.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AudioAnalysis {
+    /// Sample peak in dBFS.
+    pub peak: Option<f64>,
+    /// RMS level in dBFS.
+    pub rms: Option<f64>,
+    /// Integrated loudness in LUFS.
+    pub integrated_loudness: Option<f64>,
+    /// Duration in seconds.
+    pub duration: Option<f64>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+    /// Gain applied during conversion in dB.
+    pub gain: f64,
+}
+
+/// Find `key` in FFmpeg's log output and parse the number following it.
+fn parse_log_value(stderr: &str, key: &str) -> Option<f64> {
+    stderr.lines().rev().find_map(|line| {
+        let idx = line.find(key)?;
+        line[idx + key.len()..]
+            .split_whitespace()
+            .next()?
+            .parse::<f64>()
+            .ok()
+    })
+}
+
+/// Parse the `Duration: HH:MM:SS.ss` line of FFmpeg's input banner.
+fn parse_duration(stderr: &str) -> Option<f64> {
+    let line = stderr
+        .lines()
+        .find(|l| l.trim_start().starts_with("Duration:"))?;
+    let value = line.trim_start()[9..].split(',').next()?.trim();
+    let mut seconds = 0.0;
+    for part in value.split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(seconds)
+}
+
+/// Parse sample rate and channel count of the first audio stream, e.g.
+/// `Stream #0:0: Audio: mp3, 44100 Hz, stereo, fltp, 128 kb/s`.
+fn parse_audio_stream(stderr: &str) -> (Option<u32>, Option<u32>) {
+    let line = match stderr.lines().find(|l| l.contains("Audio:")) {
+        Some(line) => line,
+        None => return (None, None),
+    };
+    let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+    let sample_rate = fields
+        .iter()
+        .find_map(|f| f.strip_suffix(" Hz")?.parse::<u32>().ok());
+    let channels = fields.iter().find_map(|f| {
+        let layout = f.split('(').next().unwrap_or(f).trim();
+        match layout {
+            "mono" => Some(1),
+            "stereo" => Some(2),
+            "2.1" | "3.0" => Some(3),
+            "quad" | "4.0" => Some(4),
+            "5.0" => Some(5),
+            "5.1" => Some(6),
+            "7.1" => Some(8),
+            _ => layout.strip_suffix(" channels")?.parse::<u32>().ok(),
+        }
+    });
+    (sample_rate, channels)
+}
+
+/// Measure `input` with `volumedetect` and `ebur128`.
+pub fn analyze_audio(input: &str) -> Result<AudioAnalysis, String> {
+    let output = cmd_hidden(&*FFMPEG_CMD.lock().unwrap())
+        .args(
+            format!(
+                "-i {} -af volumedetect,ebur128=framelog=quiet -f null -",
+                input
+            )
+            .split_whitespace(),
+        )
+        .output()
+        .map_err(|e| e.to_string())?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(format!("FFmpeg failed to analyze {}", input));
+    }
+
+    let (sample_rate, channels) = parse_audio_stream(&stderr);
+    Ok(AudioAnalysis {
+        peak: parse_log_value(&stderr, "max_volume:"),
+        rms: parse_log_value(&stderr, "mean_volume:"),
+        integrated_loudness: parse_log_value(&stderr, " I:"),
+        duration: parse_duration(&stderr),
+        sample_rate,
+        channels,
+        gain: 0.0,
+    })
+}
+
+/// Gain in dB bringing `peak` to 0 dBFS. Silent inputs, whose peak
+/// `volumedetect` reports as `-inf`, are left as they are.
+fn normalization_gain(peak: Option<f64>) -> f64 {
+    match peak {
+        Some(peak) if peak.is_finite() => -peak,
+        _ => 0.0,
+    }
+}
+
+/// Convert `input` to a 48 kHz float WAV, optionally normalizing its peak to
+/// 0 dBFS. Blocks until FFmpeg is done.
+pub fn convert_audio_sync(
+    input: &str,
+    output: &str,
+    normalize: bool,
+) -> Result<AudioAnalysis, String> {
+    let mut analysis = analyze_audio(input)?;
+    if normalize {
+        analysis.gain = normalization_gain(analysis.peak);
+    }
+
+    let status = cmd_hidden(&*FFMPEG_CMD.lock().unwrap())
+        .args(
+            format!(
+                "-i {} -af volume={}dB -ar 48000 -c:a pcm_f32le -y {}",
+                input, analysis.gain, output
+            )
+            .split_whitespace(),
+        )
+        .status()
+        .map_err(|e| e.to_string())?;
+
+    if status.success() {
+        Ok(analysis)
+    } else {
+        Err(format!("FFmpeg failed to convert {}", input))
+    }
+}
+
+/// Convert `input` as `convert_audio_sync` does, resolving blobs, and emit
+/// `audio-conversion-finished` with the analysis or `audio-conversion-failed`.
+/// Blocks until FFmpeg is done, so callers run it off the main thread.
+pub fn convert_audio(
+    app: &AppHandle,
+    input: String,
+    output: &str,
+    normalize: bool,
+) -> Result<AudioAnalysis, String> {
+    let result = crate::blob::resolve_path(input)
+        .and_then(|input| convert_audio_sync(&input, output, normalize));
+    match &result {
+        Ok(analysis) => {
+            app.emit("audio-conversion-finished", analysis).unwrap();
+            crate::ws_server::broadcast_event(
+                "audio-conversion-finished",
+                serde_json::to_value(analysis).unwrap(),
+            );
+        }
+        Err(e) => {
+            eprintln!("[TAURI] Audio conversion failed: {}", e);
+            app.emit("audio-conversion-failed", e).unwrap();
+            crate::ws_server::broadcast_event("audio-conversion-failed", serde_json::json!(e));
+        }
+    }
+    result
+}
+
+/// Slowest playback rate of a render.
+pub const MIN_PLAYBACK_RATE: f64 = 0.25;
+
+/// Fastest playback rate of a render.
+pub const MAX_PLAYBACK_RATE: f64 = 4.0;
+
+/// Check that `rate` is a supported playback rate of a render.
+pub fn check_playback_rate(rate: f64) -> Result<(), String> {
+    if (MIN_PLAYBACK_RATE..=MAX_PLAYBACK_RATE).contains(&rate) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Playback rate must be between {} and {}, got {}",
+            MIN_PLAYBACK_RATE, MAX_PLAYBACK_RATE, rate
+        ))
+    }
+}
+
+/// Chart information written as tags of an exported audio file.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ChartMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub charter: Option<String>,
+    pub level: Option<String>,
+}
+
+/// FFmpeg codec arguments for an audio export to `output`, chosen from its
+/// extension. `bitrate` applies to MP3 and Ogg, `bit_depth` to FLAC (16 or
+/// 24, default 24) and WAV (16, 24 or 32 for float, default 32).
+pub fn export_codec(output: &str, bitrate: &str, bit_depth: Option<u16>) -> Result<String, String> {
+    let extension = std::path::Path::new(output)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match (extension.as_str(), bit_depth) {
+        ("mp3", _) => Ok(format!("-c:a libmp3lame -b:a {} -id3v2_version 3", bitrate)),
+        ("ogg", _) => Ok(format!("-c:a libvorbis -b:a {}", bitrate)),
+        ("flac", Some(16)) => Ok("-c:a flac -sample_fmt s16".to_string()),
+        ("flac", None | Some(24)) => {
+            Ok("-c:a flac -sample_fmt s32 -bits_per_raw_sample 24".to_string())
+        }
+        ("wav", Some(16)) => Ok("-c:a pcm_s16le".to_string()),
+        ("wav", Some(24)) => Ok("-c:a pcm_s24le".to_string()),
+        ("wav", None | Some(32)) => Ok("-c:a pcm_f32le".to_string()),
+        ("flac" | "wav", Some(bits)) => Err(format!(
+            "Unsupported {} bit depth: {}",
+            extension.to_uppercase(),
+            bits
+        )),
+        _ => Err(format!(
+            "Unsupported export format: {}; expected mp3, ogg, flac or wav",
+            output
+        )),
+    }
+}
+
+/// Encode `input` to `output` with the arguments from `export_codec`,
+/// tagging it with `metadata`. MP3 gets ID3v2 frames and Ogg and FLAC get
+/// Vorbis comments, with the charter and level as custom fields; WAV only
+/// keeps the title and artist. Blocks until FFmpeg is done.
+pub fn encode_audio_sync(
+    input: &str,
+    output: &str,
+    codec: &str,
+    metadata: &ChartMetadata,
+) -> Result<(), String> {
+    let mut cmd = command();
+    cmd.args(["-v", "error", "-i", input, "-map_metadata", "-1"])
+        .args(codec.split_whitespace());
+    for (key, value) in [
+        ("title", &metadata.title),
+        ("artist", &metadata.artist),
+        ("charter", &metadata.charter),
+        ("level", &metadata.level),
+    ] {
+        if let Some(value) = value {
+            // Passed as one argument, as tags may contain spaces
+            cmd.arg("-metadata").arg(format!("{}={}", key, value));
+        }
+    }
+
+    let result = cmd
+        .arg("-y")
+        .arg(output)
+        .output()
+        .map_err(|e| e.to_string())?;
+    if result.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "FFmpeg failed to encode {}: {}",
+            output,
+            String::from_utf8_lossy(&result.stderr).trim()
+        ))
+    }
+}
+
+/// Default start of the music in seconds, as the renderer starts the chart
+/// one second into the video.
+pub const DEFAULT_MUSIC_OFFSET: f64 = 1.0;
+
+/// Timing adjustments applied to the audio tracks in `combine_streams`.
+///
+/// Offsets are in seconds relative to the start of the video, at normal speed.
+/// Positive values delay a track; negative values trim the beginning of it
+/// instead.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AudioTiming {
+    pub music_offset: f64,
+    pub hitsound_offset: f64,
+    pub fade_in: f64,
+    pub fade_out: f64,
+    /// Seconds of audio kept after the video ends. When unset, the mix is left
+    /// at its natural length (unless a fade-out is requested, which ends at
+    /// the end of the video).
+    pub tail: Option<f64>,
+    /// Speed of the render. The music is time-stretched to it with its
+    /// pitch preserved and both offsets are divided by it; the hitsounds, or
+    /// a premixed input, must already be mixed at this rate.
+    pub playback_rate: f64,
+}
+
+impl Default for AudioTiming {
+    fn default() -> Self {
+        Self {
+            music_offset: DEFAULT_MUSIC_OFFSET,
+            hitsound_offset: 0.0,
+            fade_in: 0.0,
+            fade_out: 0.0,
+            tail: None,
+            playback_rate: 1.0,
+        }
+    }
+}
+
+/// EBU R128 loudness target for the final mix.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LoudnessTarget {
+    /// Integrated loudness in LUFS.
+    pub integrated: f64,
+    /// Maximum true peak in dBTP.
+    pub true_peak: f64,
+    /// Loudness range in LU.
+    pub range: f64,
+}
+
+impl Default for LoudnessTarget {
+    fn default() -> Self {
+        Self {
+            integrated: -14.0,
+            true_peak: -1.0,
+            range: 7.0,
+        }
+    }
+}
+
+/// Loudness of the mix as measured by the first `loudnorm` pass.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoudnessReport {
+    pub input_integrated: f64,
+    pub input_true_peak: f64,
+    pub input_range: f64,
+    pub input_threshold: f64,
+    pub target_offset: f64,
+}
+
+impl LoudnessReport {
+    /// Parse the JSON block printed by `loudnorm=print_format=json`.
+    fn parse(stderr: &str) -> Option<Self> {
+        let start = stderr.rfind("Parsed_loudnorm")?;
+        let json_start = start + stderr[start..].find('{')?;
+        let json_end = json_start + stderr[json_start..].find('}')?;
+        let values: HashMap<String, String> =
+            serde_json::from_str(&stderr[json_start..=json_end]).ok()?;
+        let get = |key: &str| values.get(key)?.parse::<f64>().ok();
+        Some(Self {
+            input_integrated: get("input_i")?,
+            input_true_peak: get("input_tp")?,
+            input_range: get("input_lra")?,
+            input_threshold: get("input_thresh")?,
+            target_offset: get("target_offset")?,
+        })
+    }
+}
+
+/// First `loudnorm` pass, only printing the measured values.
+fn loudnorm_measure_filter(target: &LoudnessTarget) -> String {
+    format!(
+        ",loudnorm=I={}:TP={}:LRA={}:print_format=json",
+        target.integrated, target.true_peak, target.range
+    )
+}
+
+/// Second `loudnorm` pass applying the measured values linearly.
+fn loudnorm_filter(target: &LoudnessTarget, measured: &LoudnessReport) -> String {
+    format!(
+        ",loudnorm=I={}:TP={}:LRA={}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true,aresample=48000",
+        target.integrated,
+        target.true_peak,
+        target.range,
+        measured.input_integrated,
+        measured.input_true_peak,
+        measured.input_range,
+        measured.input_threshold,
+        measured.target_offset
+    )
+}
+
+/// Run the measuring pass over the mix described by `filter_complex`.
+fn measure_loudness(inputs: &str, filter_complex: &str) -> Result<LoudnessReport, String> {
+    let output = cmd_hidden(&*FFMPEG_CMD.lock().unwrap())
+        .args(
+            format!(
+                "{} -filter_complex {} -map [a] -f null -",
+                inputs, filter_complex
+            )
+            .split_whitespace(),
+        )
+        .output()
+        .map_err(|e| e.to_string())?;
+    LoudnessReport::parse(&String::from_utf8_lossy(&output.stderr))
+        .ok_or_else(|| "Unable to read loudness measurement from FFmpeg".to_string())
+}
+
+/// Filter prefix shifting a track by `offset` seconds.
+fn offset_filter(offset: f64) -> String {
+    if offset > 0.0 {
+        let ms = (offset * 1000.0).round() as u64;
+        format!("adelay={}|{},", ms, ms)
+    } else if offset < 0.0 {
+        format!("atrim=start={:.6},asetpts=PTS-STARTPTS,", -offset)
+    } else {
+        String::new()
+    }
+}
+
+/// Filter prefix time-stretching a track to play `rate` times as fast.
+/// `atempo` takes at most halving per instance, so slower rates are
+/// chained.
+fn tempo_filter(rate: f64) -> String {
+    let mut filter = String::new();
+    let mut rate = rate;
+    while rate < 0.5 {
+        filter.push_str("atempo=0.5,");
+        rate /= 0.5;
+    }
+    if rate != 1.0 {
+        filter.push_str(&format!("atempo={:.6},", rate));
+    }
+    filter
+}
+
+/// Build the `-filter_complex` graph mixing the music (input 1) and the
+/// hitsounds (input 2) into `[a]`. A `premixed` input 1 is the final mix
+/// from `mix_audio` and only gets the fades, tail and `post`.
+///
+/// `video_duration` is only needed for the tail and fade-out, which are
+/// skipped when it is unknown. With `separate_tracks`, the aligned music and
+/// hitsounds are additionally exposed as `[ms]` and `[hs]`. `post` is appended
+/// to the end of the mix chain.
+fn build_audio_filter(
+    music_volume: f32,
+    timing: &AudioTiming,
+    video_duration: Option<f64>,
+    separate_tracks: bool,
+    premixed: bool,
+    post: &str,
+) -> String {
+    let (music_out, hitsounds_out) = if separate_tracks {
+        ("asplit=2[m][ms]", "asplit=2[h][hs]")
+    } else {
+        ("anull[m]", "anull[h]")
+    };
+    let mut filter = if premixed {
+        "[1:a]anull".to_string()
+    } else {
+        format!(
+            "[1:a]{}{}volume={},{};[2:a]{}{};[h][m]amix=inputs=2:normalize=0,alimiter=limit=1.0:level=false:attack=0.1:release=1",
+            tempo_filter(timing.playback_rate),
+            offset_filter(timing.music_offset / timing.playback_rate),
+            music_volume,
+            music_out,
+            offset_filter(timing.hitsound_offset / timing.playback_rate),
+            hitsounds_out,
+        )
+    };
+
+    if timing.fade_in > 0.0 {
+        filter.push_str(&format!(",afade=t=in:st=0:d={:.6}", timing.fade_in));
+    }
+
+    if let Some(duration) = video_duration {
+        let tail = timing.tail.or((timing.fade_out > 0.0).then_some(0.0));
+        if let Some(tail) = tail {
+            let end = duration + tail.max(0.0);
+            filter.push_str(&format!(",apad=whole_dur={:.6},atrim=end={:.6}", end, end));
+            if timing.fade_out > 0.0 {
+                let fade = timing.fade_out.min(end);
+                filter.push_str(&format!(",afade=t=out:st={:.6}:d={:.6}", end - fade, fade));
             }
+        }
+    }
 
-            let result = cmd_hidden(&*FFMPEG_CMD.lock().unwrap())
-                .args(
-                    format!(
-                        "-i {} -af volume={}dB -ar 48000 -c:a pcm_f32le -y {}",
-                        input, gain, output
-                    )
-                    .split_whitespace(),
-                )
-                .status()
-                .map_err(|e| e.to_string());
-
-            match result {
-                Ok(_) => {
-                    app.emit("audio-conversion-finished", ()).unwrap();
-                    crate::ws_server::broadcast_event(
-                        "audio-conversion-finished",
-                        serde_json::Value::Null,
-                    );
-                }
-                Err(e) => {
-                    eprintln!("[TAURI] Audio conversion failed: {}", e);
-                }
+    filter.push_str(post);
+    filter.push_str("[a]");
+    filter
+}
+
+/// Read the duration of a media file from FFmpeg's stderr banner.
+fn probe_duration(input: &str) -> Option<f64> {
+    let output = cmd_hidden(&*FFMPEG_CMD.lock().unwrap())
+        .args(["-i", input])
+        .output()
+        .ok()?;
+    parse_duration(&String::from_utf8_lossy(&output.stderr))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    Mp4,
+    Mkv,
+    Webm,
+    Mov,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCodec {
+    Aac,
+    Opus,
+    Flac,
+    Mp3,
+    Pcm,
+}
+
+/// Container and audio codec of the final output. Unset fields are inferred
+/// from the output extension and the container defaults.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OutputFormat {
+    pub container: Option<Container>,
+    pub audio_codec: Option<AudioCodec>,
+    /// Also write the music and the hitsounds as their own audio streams
+    /// after the mix (MKV and MOV only).
+    pub separate_tracks: bool,
+    /// ISO 639-2 language code tagged on the audio streams.
+    pub language: Option<String>,
+}
+
+/// Titles of the audio streams written with `separate_tracks`, in output order.
+const TRACK_TITLES: [&str; 3] = ["Mix", "Music", "Hitsounds"];
+
+impl Container {
+    fn from_path(path: &str) -> Option<Self> {
+        let ext = std::path::Path::new(path)
+            .extension()?
+            .to_string_lossy()
+            .to_lowercase();
+        match ext.as_str() {
+            "mp4" | "m4v" => Some(Self::Mp4),
+            "mkv" => Some(Self::Mkv),
+            "webm" => Some(Self::Webm),
+            "mov" => Some(Self::Mov),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Mp4 => "MP4",
+            Self::Mkv => "MKV",
+            Self::Webm => "WebM",
+            Self::Mov => "MOV",
+        }
+    }
+
+    fn muxer(self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4",
+            Self::Mkv => "matroska",
+            Self::Webm => "webm",
+            Self::Mov => "mov",
+        }
+    }
+
+    /// Flags moving the index to the front of the file. Cues at the front
+    /// of Matroska need FFmpeg 5.1; older versions keep them at the end.
+    fn muxer_flags(self) -> &'static str {
+        match self {
+            Self::Mp4 | Self::Mov => "-movflags +faststart",
+            Self::Mkv | Self::Webm if muxer_has_option(self.muxer(), "cues_to_front") => {
+                "-cues_to_front 1"
             }
+            Self::Mkv | Self::Webm => "",
         }
-    });
+    }
+
+    fn default_audio_codec(self) -> AudioCodec {
+        match self {
+            Self::Webm => AudioCodec::Opus,
+            _ => AudioCodec::Aac,
+        }
+    }
+
+    /// Whether a video stream of the given codec (as named by FFmpeg) can be
+    /// copied into this container.
+    fn supports_video(self, codec: &str) -> bool {
+        match self {
+            Self::Mp4 => matches!(codec, "h264" | "hevc" | "av1" | "vp9" | "mpeg4"),
+            Self::Mkv => true,
+            Self::Webm => matches!(codec, "vp8" | "vp9" | "av1"),
+            Self::Mov => matches!(codec, "h264" | "hevc" | "prores" | "mpeg4"),
+        }
+    }
+
+    fn supports_audio(self, codec: AudioCodec) -> bool {
+        match self {
+            Self::Mp4 => codec != AudioCodec::Pcm,
+            Self::Mkv => true,
+            Self::Webm => codec == AudioCodec::Opus,
+            Self::Mov => matches!(codec, AudioCodec::Aac | AudioCodec::Mp3 | AudioCodec::Pcm),
+        }
+    }
+}
+
+/// Whether FFmpeg's `muxer` has the private `option`.
+fn muxer_has_option(muxer: &str, option: &str) -> bool {
+    command()
+        .args(["-hide_banner", "-h", &format!("muxer={}", muxer)])
+        .output()
+        .is_ok_and(|output| {
+            String::from_utf8_lossy(&output.stdout).contains(&format!("-{} ", option))
+        })
+}
+
+impl AudioCodec {
+    fn encoder(self) -> &'static str {
+        match self {
+            Self::Aac => "aac",
+            Self::Opus => "libopus",
+            Self::Flac => "flac",
+            Self::Mp3 => "libmp3lame",
+            Self::Pcm => "pcm_s16le",
+        }
+    }
+
+    fn is_lossless(self) -> bool {
+        matches!(self, Self::Flac | Self::Pcm)
+    }
+}
+
+/// Resolve the codec implemented by an FFmpeg encoder (e.g. `libx264` -> `h264`).
+fn video_codec_of(encoder: &str) -> String {
+    get_encoders()
+        .ok()
+        .and_then(|encoders| encoders.into_iter().find(|e| e.name == encoder))
+        .and_then(|e| e.codec)
+        .unwrap_or_else(|| encoder.to_string())
+}
+
+/// Work out the container and audio codec for `output`, checking that both
+/// the audio codec and the codec of `video_encoder`, if known, fit into it.
+fn resolve_output_format(
+    format: &OutputFormat,
+    output: &str,
+    video_encoder: Option<&str>,
+) -> Result<(Container, AudioCodec), String> {
+    let container = match format.container.or_else(|| Container::from_path(output)) {
+        Some(container) => container,
+        None => {
+            return Err(format!(
+                "Unable to infer the container from '{}'; please specify one",
+                output
+            ))
+        }
+    };
+    let audio_codec = format
+        .audio_codec
+        .unwrap_or_else(|| container.default_audio_codec());
+
+    if format.separate_tracks && !matches!(container, Container::Mkv | Container::Mov) {
+        return Err(format!(
+            "Separate audio tracks require MKV or MOV, not {}",
+            container.name()
+        ));
+    }
+
+    if !container.supports_audio(audio_codec) {
+        return Err(format!(
+            "Audio codec {:?} is not supported in {}",
+            audio_codec,
+            container.name()
+        ));
+    }
+
+    if let Some(encoder) = video_encoder {
+        let codec = video_codec_of(encoder);
+        if !container.supports_video(&codec) {
+            return Err(format!(
+                "Video codec {} (encoder {}) is not supported in {}",
+                codec,
+                encoder,
+                container.name()
+            ));
+        }
+    }
+
+    Ok((container, audio_codec))
+}
+
+/// Inputs and output arguments of a stream combination, checked before it
+/// starts.
+struct Combination {
+    input_video: String,
+    input_music: String,
+    input_hitsounds: String,
+    /// Without hitsounds, the music input is a final mix from `mix_audio`
+    premixed: bool,
+    container: Container,
+    audio_args: String,
+    maps: &'static str,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn prepare_combination(
+    input_video: String,
+    input_music: String,
+    input_hitsounds: String,
+    audio_bitrate: &str,
+    timing: &AudioTiming,
+    format: &OutputFormat,
+    video_encoder: Option<&str>,
+    output: &str,
+) -> Result<Combination, String> {
+    check_playback_rate(timing.playback_rate)?;
+    let input_music = crate::blob::resolve_path(input_music)?;
+    let input_hitsounds = crate::blob::resolve_path(input_hitsounds)?;
+    let premixed = input_hitsounds.is_empty();
+    if premixed && format.separate_tracks {
+        return Err("Separate tracks need separate music and hitsound inputs".to_string());
+    }
+    let (container, audio_codec) = resolve_output_format(format, output, video_encoder)?;
+    let mut audio_args = if audio_codec.is_lossless() {
+        format!("-c:a {}", audio_codec.encoder())
+    } else {
+        format!("-b:a {} -c:a {}", audio_bitrate, audio_codec.encoder())
+    };
+    // Older FFmpeg only muxes FLAC and Opus into MP4 as experimental
+    if container == Container::Mp4 && matches!(audio_codec, AudioCodec::Flac | AudioCodec::Opus) {
+        audio_args.push_str(" -strict experimental");
+    }
+    let track_count = if format.separate_tracks { 3 } else { 1 };
+    let language = format.language.as_deref().unwrap_or("und");
+    for (i, title) in TRACK_TITLES.iter().take(track_count).enumerate() {
+        audio_args.push_str(&format!(
+            " -metadata:s:a:{} title={} -metadata:s:a:{} language={}",
+            i, title, i, language
+        ));
+    }
+    let maps = if format.separate_tracks {
+        "-map 0:v:0 -map [a] -map [ms] -map [hs]"
+    } else {
+        "-map 0:v:0 -map [a]"
+    };
+
+    Ok(Combination {
+        input_video,
+        input_music,
+        input_hitsounds,
+        premixed,
+        container,
+        audio_args,
+        maps,
+    })
+}
+
+fn combine_sync(
+    app: &AppHandle,
+    combination: &Combination,
+    music_volume: f32,
+    timing: &AudioTiming,
+    separate_tracks: bool,
+    loudness: Option<&LoudnessTarget>,
+    output: &str,
+) -> Result<(), String> {
+    print!("[TAURI] Combining streams...");
+    let video_duration = if timing.tail.is_some() || timing.fade_out > 0.0 {
+        probe_duration(&combination.input_video)
+    } else {
+        None
+    };
+    let inputs = if combination.premixed {
+        format!(
+            "-y -i {} -i {}",
+            combination.input_video, combination.input_music
+        )
+    } else {
+        format!(
+            "-y -i {} -i {} -i {}",
+            combination.input_video, combination.input_music, combination.input_hitsounds
+        )
+    };
 
+    let mut post = String::new();
+    if let Some(target) = loudness {
+        let measure_filter = build_audio_filter(
+            music_volume,
+            timing,
+            video_duration,
+            false,
+            combination.premixed,
+            &loudnorm_measure_filter(target),
+        );
+        match measure_loudness(&inputs, &measure_filter) {
+            Ok(report) => {
+                print!(
+                    " measured {:.1} LUFS / {:.1} dBTP...",
+                    report.input_integrated, report.input_true_peak
+                );
+                post = loudnorm_filter(target, &report);
+                app.emit("loudness-measured", &report).unwrap();
+                crate::ws_server::broadcast_event(
+                    "loudness-measured",
+                    serde_json::to_value(&report).unwrap(),
+                );
+            }
+            Err(e) => return Err(format!("Loudness measurement failed: {}", e)),
+        }
+    }
+
+    let filter_complex = build_audio_filter(
+        music_volume,
+        timing,
+        video_duration,
+        separate_tracks,
+        combination.premixed,
+        &post,
+    );
+    let status = cmd_hidden(&*FFMPEG_CMD.lock().unwrap())
+        .args(format!("{} -filter_complex {}", inputs, filter_complex).split_whitespace())
+        .args(
+            format!(
+                "{} {} -c:v copy {} -f {}",
+                combination.maps,
+                combination.audio_args,
+                combination.container.muxer_flags(),
+                combination.container.muxer()
+            )
+            .split_whitespace(),
+        )
+        .arg(output)
+        .status()
+        .map_err(|e| e.to_string())?;
+    if !status.success() {
+        return Err(format!("FFmpeg failed with status: {}", status));
+    }
+    println!(" finished.");
     Ok(())
 }
 
+/// Combine the streams on the calling thread, for callers that are already
+/// in the background. `video_encoder` is the encoder of `input_video`, if
+/// known, to check that it fits into the output container.
+#[allow(clippy::too_many_arguments)]
+pub fn combine_streams_sync(
+    app: &AppHandle,
+    input_video: String,
+    input_music: String,
+    input_hitsounds: String,
+    music_volume: f32,
+    audio_bitrate: &str,
+    timing: &AudioTiming,
+    format: &OutputFormat,
+    loudness: Option<&LoudnessTarget>,
+    video_encoder: Option<&str>,
+    output: &str,
+) -> Result<(), String> {
+    let combination = prepare_combination(
+        input_video,
+        input_music,
+        input_hitsounds,
+        audio_bitrate,
+        timing,
+        format,
+        video_encoder,
+        output,
+    )?;
+    combine_sync(
+        app,
+        &combination,
+        music_volume,
+        timing,
+        format.separate_tracks,
+        loudness,
+        output,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn combine_streams(
     app: AppHandle,
     input_video: String,
@@ -187,49 +1015,49 @@ pub fn combine_streams(
     input_hitsounds: String,
     music_volume: f32,
     audio_bitrate: String,
+    timing: AudioTiming,
+    format: OutputFormat,
+    loudness: Option<LoudnessTarget>,
     output: String,
 ) -> Result<(), String> {
+    let video_encoder = VIDEO_ENCODER.lock().unwrap().clone();
+    let combination = prepare_combination(
+        input_video,
+        input_music,
+        input_hitsounds,
+        &audio_bitrate,
+        &timing,
+        &format,
+        video_encoder.as_deref(),
+        &output,
+    )?;
+
     send_webhook_notification("combining_streams", 0.0, None);
 
-    std::thread::spawn({
-        let app = app.clone();
-        move || {
-            print!("[TAURI] Combining streams...");
-            let filter_complex = format!(
-                "[1:a]adelay=1000|1000,volume={}[a2];[2:a][a2]amix=inputs=2:normalize=0,alimiter=limit=1.0:level=false:attack=0.1:release=1[a]",
-                music_volume
-            );
-            let result = cmd_hidden(&*FFMPEG_CMD.lock().unwrap())
-                .args(
-                    format!(
-                        "-y -i {} -i {} -i {} -filter_complex {}",
-                        input_video, input_music, input_hitsounds, filter_complex
-                    )
-                    .split_whitespace(),
-                )
-                .args(
-                    format!(
-                        "-map 0:v:0 -map [a] -b:a {} -c:a aac -c:v copy -movflags +faststart",
-                        audio_bitrate
-                    )
-                    .split_whitespace(),
-                )
-                .arg(&output)
-                .status()
-                .map_err(|e| e.to_string());
-
-            match result {
-                Ok(_) => {
-                    app.emit("stream-combination-finished", &output).unwrap();
-                    crate::ws_server::broadcast_event(
-                        "stream-combination-finished",
-                        serde_json::Value::String(output),
-                    );
-                    println!(" finished.");
-                }
-                Err(e) => {
-                    eprintln!("[TAURI] Stream combination failed: {}", e);
-                }
+    std::thread::spawn(move || {
+        match combine_sync(
+            &app,
+            &combination,
+            music_volume,
+            &timing,
+            format.separate_tracks,
+            loudness.as_ref(),
+            &output,
+        ) {
+            Ok(()) => {
+                app.emit("stream-combination-finished", &output).unwrap();
+                crate::ws_server::broadcast_event(
+                    "stream-combination-finished",
+                    serde_json::Value::String(output),
+                );
+            }
+            Err(e) => {
+                eprintln!("\n[TAURI] Stream combination failed: {}", e);
+                app.emit("stream-combination-failed", &e).unwrap();
+                crate::ws_server::broadcast_event(
+                    "stream-combination-failed",
+                    serde_json::json!(e),
+                );
             }
         }
     });
@@ -237,8 +1065,32 @@ pub fn combine_streams(
     Ok(())
 }
 
+/// Spawn an FFmpeg process encoding raw RGB frames written to its stdin
+/// to `output`.
+pub fn spawn_video_encoder(
+    output: &str,
+    resolution: &str,
+    framerate: u32,
+    codec: &str,
+    bitrate: &str,
+) -> Result<Child, String> {
+    cmd_hidden(&*FFMPEG_CMD.lock().unwrap())
+        .args(format!(
+            "-probesize 50M -f rawvideo -pix_fmt rgb24 -s {} -r {} -thread_queue_size 1024 -i pipe:0 -c:v {} -b:v {} -vf vflip -pix_fmt yuv420p -movflags +faststart -y {}",
+            resolution, framerate, codec, bitrate, output
+        )
+        .split_whitespace())
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())
+}
+
 /// Spawn the FFmpeg process for video encoding. Returns (total_frames, report_interval).
 ///
+/// `duration` is the length of the chart at normal speed; at another
+/// `playback_rate` the video gets `duration / playback_rate` seconds of
+/// frames.
+///
 /// Frame data is fed to FFmpeg through the global `FFMPEG_STDIN` by the
 /// WebSocket server (see `ws_server.rs`).
 pub fn setup_video_process(
@@ -248,22 +1100,17 @@ pub fn setup_video_process(
     duration: f64,
     codec: String,
     bitrate: String,
+    playback_rate: f64,
 ) -> Result<(u64, u32), String> {
-    let mut process = cmd_hidden(&*FFMPEG_CMD.lock().unwrap())
-        .args(format!(
-            "-probesize 50M -f rawvideo -pix_fmt rgb24 -s {} -r {} -thread_queue_size 1024 -i pipe:0 -c:v {} -b:v {} -vf vflip -pix_fmt yuv420p -movflags +faststart -y {}",
-            resolution, framerate.to_string(), codec, bitrate, output
-        )
-        .split_whitespace())
-        .stdin(std::process::Stdio::piped())
-        .spawn()
-        .map_err(|e| e.to_string())?;
+    check_playback_rate(playback_rate)?;
+    let mut process = spawn_video_encoder(&output, &resolution, framerate, &codec, &bitrate)?;
 
     let stdin = process.stdin.take();
     *FFMPEG_STDIN.lock().unwrap() = stdin;
+    *VIDEO_ENCODER.lock().unwrap() = Some(codec);
     *FFMPEG_PROCESS.lock().unwrap() = Some(process);
 
-    let total_frames = (duration * framerate as f64).ceil() as u64;
+    let total_frames = (duration / playback_rate * framerate as f64).ceil() as u64;
     let report_interval = get_report_interval();
     println!("[TAURI] FFmpeg setup complete");
 
@@ -317,3 +1164,142 @@ pub fn finish_video() -> Result<(), String> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(fade_in: f64, fade_out: f64, tail: Option<f64>) -> AudioTiming {
+        AudioTiming {
+            music_offset: 1.0,
+            hitsound_offset: -0.1,
+            fade_in,
+            fade_out,
+            tail,
+            playback_rate: 1.0,
+        }
+    }
+
+    #[test]
+    fn silent_input_is_not_normalized() {
+        assert_eq!(normalization_gain(Some(-6.5)), 6.5);
+        assert_eq!(normalization_gain(Some(f64::NEG_INFINITY)), 0.0);
+        assert_eq!(normalization_gain(Some(f64::NAN)), 0.0);
+        assert_eq!(normalization_gain(None), 0.0);
+    }
+
+    #[test]
+    fn offset_filter_delays_or_trims() {
+        assert_eq!(offset_filter(0.5), "adelay=500|500,");
+        assert_eq!(
+            offset_filter(-0.25),
+            "atrim=start=0.250000,asetpts=PTS-STARTPTS,"
+        );
+        assert_eq!(offset_filter(0.0), "");
+    }
+
+    #[test]
+    fn audio_filter_offsets_tracks() {
+        assert_eq!(
+            build_audio_filter(0.8, &timing(0.0, 0.0, None), Some(10.0), false, false, ""),
+            "[1:a]adelay=1000|1000,volume=0.8,anull[m];\
+             [2:a]atrim=start=0.100000,asetpts=PTS-STARTPTS,anull[h];\
+             [h][m]amix=inputs=2:normalize=0,alimiter=limit=1.0:level=false:attack=0.1:release=1[a]"
+        );
+    }
+
+    #[test]
+    fn audio_filter_scales_offsets_to_playback_rate() {
+        let timing = AudioTiming {
+            playback_rate: 2.0,
+            ..timing(0.0, 0.0, None)
+        };
+        assert!(
+            build_audio_filter(1.0, &timing, None, false, false, "").starts_with(
+                "[1:a]atempo=2.000000,adelay=500|500,volume=1,anull[m];\
+             [2:a]atrim=start=0.050000,asetpts=PTS-STARTPTS,anull[h];"
+            )
+        );
+    }
+
+    #[test]
+    fn audio_filter_fades_and_tail() {
+        let filter = build_audio_filter(
+            1.0,
+            &timing(2.0, 3.0, Some(1.5)),
+            Some(10.0),
+            false,
+            false,
+            "",
+        );
+        assert!(filter.ends_with(
+            ",afade=t=in:st=0:d=2.000000\
+             ,apad=whole_dur=11.500000,atrim=end=11.500000\
+             ,afade=t=out:st=8.500000:d=3.000000[a]"
+        ));
+
+        // A fade-out without a tail ends with the video
+        let filter = build_audio_filter(1.0, &timing(0.0, 3.0, None), Some(10.0), false, false, "");
+        assert!(filter.ends_with(
+            ",apad=whole_dur=10.000000,atrim=end=10.000000\
+             ,afade=t=out:st=7.000000:d=3.000000[a]"
+        ));
+
+        // A fade-out longer than the output is cut to it
+        let filter =
+            build_audio_filter(1.0, &timing(0.0, 30.0, None), Some(10.0), false, false, "");
+        assert!(filter.ends_with(",afade=t=out:st=0.000000:d=10.000000[a]"));
+
+        // The tail and fade-out need the video duration
+        let filter = build_audio_filter(1.0, &timing(0.0, 3.0, Some(1.5)), None, false, false, "");
+        assert!(!filter.contains("apad") && !filter.contains("afade"));
+    }
+
+    #[test]
+    fn audio_filter_premixed_input() {
+        assert_eq!(
+            build_audio_filter(
+                0.8,
+                &timing(1.0, 0.0, Some(0.5)),
+                Some(4.0),
+                false,
+                true,
+                ",post"
+            ),
+            "[1:a]anull,afade=t=in:st=0:d=1.000000\
+             ,apad=whole_dur=4.500000,atrim=end=4.500000,post[a]"
+        );
+    }
+
+    #[test]
+    fn flac_and_opus_in_mp4_are_marked_experimental() {
+        let combine = |audio_codec| {
+            let format = OutputFormat {
+                audio_codec: Some(audio_codec),
+                ..Default::default()
+            };
+            let timing = timing(0.0, 0.0, None);
+            prepare_combination(
+                "v.mp4".to_string(),
+                "m.ogg".to_string(),
+                "h.wav".to_string(),
+                "320k",
+                &timing,
+                &format,
+                Some("h264"),
+                "out.mp4",
+            )
+            .unwrap()
+            .audio_args
+        };
+        assert!(combine(AudioCodec::Flac).contains("-strict experimental"));
+        assert!(combine(AudioCodec::Opus).contains("-strict experimental"));
+        assert!(!combine(AudioCodec::Aac).contains("-strict"));
+    }
+
+    #[test]
+    fn default_timing_delays_music_by_a_second() {
+        let filter = build_audio_filter(1.0, &AudioTiming::default(), None, false, false, "");
+        assert!(filter.starts_with("[1:a]adelay=1000|1000,"), "{}", filter);
+    }
+}
@@ -7,6 +7,8 @@ use tauri::{AppHandle, Emitter};
 use url::Url;
 
 mod audio;
+mod blob;
+mod calibration;
 mod ffmpeg;
 pub mod ws_server;
 
@@ -76,6 +78,9 @@ pub fn run() {
                 )?;
             }
 
+            // Blob files of a previous run are of no use anymore.
+            blob::remove_stale();
+
             // Start the always-on WebSocket server for IPC + frame transfer.
             let handle = app.handle().clone();
             tauri::async_runtime::spawn(async move {
@@ -138,11 +143,29 @@ pub fn run() {
             finish_video,
             combine_streams,
             mix_audio,
+            export_audio,
+            validate_mix,
+            get_waveform,
+            analyze_offset,
+            audio_preload,
+            audio_unload,
+            audio_play,
+            audio_stop,
+            audio_seek,
+            generate_calibration_video,
+            get_audio_cache_info,
+            clear_audio_cache,
+            release_blob,
             console_log,
             close
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|_, event| {
+            if let tauri::RunEvent::Exit = event {
+                blob::release_all();
+            }
+        });
 }
 
 pub fn send_webhook_notification(status: &str, progress: f64, eta_seconds: Option<f64>) {
@@ -385,8 +408,17 @@ fn get_ffmpeg_encoders() -> Result<Vec<ffmpeg::Encoder>, String> {
 }
 
 #[tauri::command]
-fn convert_audio(app: AppHandle, input: String, output: String) -> Result<(), String> {
-    ffmpeg::convert_audio(app, input, output)
+async fn convert_audio(
+    app: AppHandle,
+    input: String,
+    output: String,
+    normalize: Option<bool>,
+) -> Result<ffmpeg::AudioAnalysis, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        ffmpeg::convert_audio(&app, input, &output, normalize.unwrap_or(true))
+    })
+    .await
+    .map_err(|e| e.to_string())?
 }
 
 /// Set up the FFmpeg video encoding process.
@@ -401,9 +433,17 @@ fn setup_video(
     duration: f64,
     codec: String,
     bitrate: String,
+    playback_rate: Option<f64>,
 ) -> Result<(), String> {
-    let (total_frames, report_interval) =
-        ffmpeg::setup_video_process(output, resolution, frame_rate, duration, codec, bitrate)?;
+    let (total_frames, report_interval) = ffmpeg::setup_video_process(
+        output,
+        resolution,
+        frame_rate,
+        duration,
+        codec,
+        bitrate,
+        playback_rate.unwrap_or(1.0),
+    )?;
 
     let mut state = ws_server::FRAME_STATE.lock().unwrap();
     state.active = true;
@@ -421,22 +461,29 @@ fn finish_video() -> Result<(), String> {
 }
 
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 fn combine_streams(
     app: AppHandle,
     input_video: String,
     input_music: String,
-    input_hitsounds: String,
+    input_hitsounds: Option<String>,
     music_volume: f32,
     audio_bitrate: String,
+    timing: Option<ffmpeg::AudioTiming>,
+    format: Option<ffmpeg::OutputFormat>,
+    loudness: Option<ffmpeg::LoudnessTarget>,
     output: String,
 ) -> Result<(), String> {
     ffmpeg::combine_streams(
         app,
         input_video,
         input_music,
-        input_hitsounds,
+        input_hitsounds.unwrap_or_default(),
         music_volume,
         audio_bitrate,
+        timing.unwrap_or_default(),
+        format.unwrap_or_default(),
+        loudness,
         output,
     )
 }
@@ -448,8 +495,133 @@ fn mix_audio(
     timestamps: Vec<audio::Timestamp>,
     length: f64,
     output: String,
+    options: Option<audio::MixOptions>,
+) -> Result<(), String> {
+    audio::mix_audio(
+        app,
+        sounds,
+        timestamps,
+        length,
+        output,
+        options.unwrap_or_default(),
+    )
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+fn export_audio(
+    app: AppHandle,
+    sounds: Vec<audio::Sound>,
+    timestamps: Vec<audio::Timestamp>,
+    length: f64,
+    output: String,
+    metadata: Option<ffmpeg::ChartMetadata>,
+    options: Option<audio::ExportOptions>,
 ) -> Result<(), String> {
-    audio::mix_audio(app, sounds, timestamps, length, output)
+    audio::export_audio(
+        app,
+        sounds,
+        timestamps,
+        length,
+        output,
+        metadata.unwrap_or_default(),
+        options.unwrap_or_default(),
+    )
+}
+
+#[tauri::command]
+fn validate_mix(
+    sounds: Vec<audio::Sound>,
+    timestamps: Vec<audio::Timestamp>,
+    length: f64,
+) -> Vec<audio::TimelineProblem> {
+    audio::validate_timeline(&sounds, &timestamps, length)
+}
+
+#[tauri::command]
+async fn get_waveform(
+    app: AppHandle,
+    path: String,
+    buckets: usize,
+    start: Option<f64>,
+    end: Option<f64>,
+) -> Result<audio::Waveform, String> {
+    // Decoding a song takes seconds, so keep it off the main thread
+    tauri::async_runtime::spawn_blocking(move || {
+        audio::get_waveform(&app, &path, buckets, start, end)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn analyze_offset(
+    path: String,
+    note_times: Vec<f64>,
+    options: Option<audio::OffsetOptions>,
+) -> Result<audio::OffsetAnalysis, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        audio::analyze_offset(&path, &note_times, &options.unwrap_or_default())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+async fn audio_preload(app: AppHandle, sounds: Vec<audio::Sound>) -> Result<String, String> {
+    tauri::async_runtime::spawn_blocking(move || audio::preload(&app, sounds).map(str::to_string))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+fn audio_unload(keys: Option<Vec<String>>) {
+    audio::unload(keys.as_deref())
+}
+
+#[tauri::command]
+fn audio_play(
+    key: String,
+    at: Option<f64>,
+    volume: Option<f32>,
+    rate: Option<f32>,
+) -> Result<(), String> {
+    audio::play(&key, at, volume.unwrap_or(1.0), rate.unwrap_or(1.0))
+}
+
+#[tauri::command]
+fn audio_stop(key: Option<String>) {
+    audio::stop(key.as_deref())
+}
+
+#[tauri::command]
+fn audio_seek(time: f64) {
+    audio::seek(time)
+}
+
+#[tauri::command]
+fn generate_calibration_video(
+    app: AppHandle,
+    output: String,
+    options: Option<calibration::CalibrationOptions>,
+) -> Result<String, String> {
+    calibration::generate_calibration_video(app, output, options.unwrap_or_default())
+}
+
+#[tauri::command]
+fn get_audio_cache_info(app: AppHandle) -> audio::CacheInfo {
+    audio::audio_cache_info(&app)
+}
+
+#[tauri::command]
+fn clear_audio_cache(app: AppHandle) -> Result<(), String> {
+    audio::clear_audio_cache(&app)
+}
+
+/// Free a blob uploaded over the WebSocket server.
+#[tauri::command]
+fn release_blob(id: String) -> Result<(), String> {
+    blob::release(&id)
 }
 
 pub fn do_console_log(message: &str, severity: &str) {
@@ -10,7 +10,7 @@ use tokio::net::TcpListener;
 use tokio::sync::{broadcast, Notify};
 use tokio_tungstenite::accept_async;
 
-use crate::{audio, ffmpeg, send_webhook_notification};
+use crate::{audio, blob, calibration, ffmpeg, send_webhook_notification};
 
 /// Port for the WebSocket server used for IPC + frame transfer.
 pub const WS_PORT: u16 = 63401;
@@ -89,7 +89,8 @@ pub async fn wait_for_ipc_client() {
 /// Start the always-on WebSocket server on port 63401.
 ///
 /// This server handles **both** IPC invoke/event messages (JSON text)
-/// **and** binary frame transfer for rendering.
+/// **and** binary frame transfer for rendering. Binary messages from a
+/// connection that has sent IPC messages are blob uploads instead of frames.
 pub async fn start(app_handle: tauri::AppHandle) {
     *APP_HANDLE.lock().unwrap() = Some(app_handle);
 
@@ -220,9 +221,27 @@ async fn handle_connection(ws_stream: tokio_tungstenite::WebSocketStream<tokio::
                 let _ = w.send(frames.to_string().into()).await;
             }
         } else if message.is_binary() {
+            let data = message.into_data();
+
+            // Binary data from an IPC client → blob upload, acknowledged
+            // with the bytes received so far.
+            if is_ipc.load(Ordering::Relaxed) {
+                let reply = match blob::parse_message(&data) {
+                    Some((id, payload)) => match blob::append(id, payload) {
+                        Ok(size) => serde_json::json!({ "type": "blob", "id": id, "size": size }),
+                        Err(e) => serde_json::json!({ "type": "blob", "id": id, "error": e }),
+                    },
+                    None => {
+                        serde_json::json!({ "type": "blob", "error": "Malformed blob message" })
+                    }
+                };
+                let mut w = write.lock().await;
+                let _ = w.send(reply.to_string().into()).await;
+                continue;
+            }
+
             // Binary data → frame data for FFmpeg.
             is_frame_connection = true;
-            let data = message.into_data();
             handle_frame_data(&data);
         }
     }
@@ -320,6 +339,15 @@ fn handle_frame_data(data: &[u8]) {
 
 // ── IPC invoke dispatch ─────────────────────────────────────────────
 
+/// Commands that can take seconds, run on the blocking thread pool so that
+/// they do not hold up the connection task.
+const BLOCKING_COMMANDS: &[&str] = &[
+    "convert_audio",
+    "get_waveform",
+    "analyze_offset",
+    "audio_preload",
+];
+
 async fn handle_invoke(json: &Value) -> String {
     let id = json["id"].as_u64().unwrap_or(0); // Default to 0 for malformed requests
     let command = json["command"].as_str().unwrap_or("");
@@ -328,7 +356,14 @@ async fn handle_invoke(json: &Value) -> String {
         .cloned()
         .unwrap_or(Value::Object(Default::default()));
 
-    let result = dispatch_command(command, &args);
+    let result = if BLOCKING_COMMANDS.contains(&command) {
+        let command = command.to_string();
+        tauri::async_runtime::spawn_blocking(move || dispatch_command(&command, &args))
+            .await
+            .unwrap_or_else(|e| Err(e.to_string()))
+    } else {
+        dispatch_command(command, &args)
+    };
 
     match result {
         Ok(value) => serde_json::json!({
@@ -381,17 +416,15 @@ fn dispatch_command(command: &str, args: &Value) -> Result<Value, String> {
         }
         "convert_audio" => {
             let input = args["input"].as_str().ok_or("Missing 'input'")?.to_string();
-            let output = args["output"]
-                .as_str()
-                .ok_or("Missing 'output'")?
-                .to_string();
+            let output = args["output"].as_str().ok_or("Missing 'output'")?;
+            let normalize = args["normalize"].as_bool().unwrap_or(true);
             let app = APP_HANDLE
                 .lock()
                 .unwrap()
                 .clone()
                 .ok_or("App handle not available")?;
-            ffmpeg::convert_audio(app, input, output)?;
-            Ok(Value::Null)
+            let analysis = ffmpeg::convert_audio(&app, input, output, normalize)?;
+            Ok(serde_json::to_value(analysis).unwrap())
         }
         "setup_video" => {
             let output = args["output"]
@@ -409,9 +442,16 @@ fn dispatch_command(command: &str, args: &Value) -> Result<Value, String> {
                 .as_str()
                 .ok_or("Missing 'bitrate'")?
                 .to_string();
+            let playback_rate = args["playbackRate"].as_f64().unwrap_or(1.0);
 
             let (total_frames, report_interval) = ffmpeg::setup_video_process(
-                output, resolution, frame_rate, duration, codec, bitrate,
+                output,
+                resolution,
+                frame_rate,
+                duration,
+                codec,
+                bitrate,
+                playback_rate,
             )?;
 
             let mut state = FRAME_STATE.lock().unwrap();
@@ -439,7 +479,7 @@ fn dispatch_command(command: &str, args: &Value) -> Result<Value, String> {
                 .to_string();
             let input_hitsounds = args["inputHitsounds"]
                 .as_str()
-                .ok_or("Missing 'inputHitsounds'")?
+                .unwrap_or_default()
                 .to_string();
             let music_volume = args["musicVolume"]
                 .as_f64()
@@ -448,6 +488,15 @@ fn dispatch_command(command: &str, args: &Value) -> Result<Value, String> {
                 .as_str()
                 .ok_or("Missing 'audioBitrate'")?
                 .to_string();
+            let timing: Option<ffmpeg::AudioTiming> =
+                serde_json::from_value(args["timing"].clone())
+                    .map_err(|e| format!("Invalid 'timing': {}", e))?;
+            let format: Option<ffmpeg::OutputFormat> =
+                serde_json::from_value(args["format"].clone())
+                    .map_err(|e| format!("Invalid 'format': {}", e))?;
+            let loudness: Option<ffmpeg::LoudnessTarget> =
+                serde_json::from_value(args["loudness"].clone())
+                    .map_err(|e| format!("Invalid 'loudness': {}", e))?;
             let output = args["output"]
                 .as_str()
                 .ok_or("Missing 'output'")?
@@ -464,6 +513,9 @@ fn dispatch_command(command: &str, args: &Value) -> Result<Value, String> {
                 input_hitsounds,
                 music_volume,
                 audio_bitrate,
+                timing.unwrap_or_default(),
+                format.unwrap_or_default(),
+                loudness,
                 output,
             )?;
             Ok(Value::Null)
@@ -479,12 +531,167 @@ fn dispatch_command(command: &str, args: &Value) -> Result<Value, String> {
                 .as_str()
                 .ok_or("Missing 'output'")?
                 .to_string();
+            let options: Option<audio::MixOptions> =
+                serde_json::from_value(args["options"].clone())
+                    .map_err(|e| format!("Invalid 'options': {}", e))?;
+            let app = APP_HANDLE
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or("App handle not available")?;
+            audio::mix_audio(
+                app,
+                sounds,
+                timestamps,
+                length,
+                output,
+                options.unwrap_or_default(),
+            )?;
+            Ok(Value::Null)
+        }
+        "export_audio" => {
+            let sounds: Vec<audio::Sound> = serde_json::from_value(args["sounds"].clone())
+                .map_err(|e| format!("Invalid 'sounds': {}", e))?;
+            let timestamps: Vec<audio::Timestamp> =
+                serde_json::from_value(args["timestamps"].clone())
+                    .map_err(|e| format!("Invalid 'timestamps': {}", e))?;
+            let length = args["length"].as_f64().ok_or("Missing 'length'")?;
+            let output = args["output"]
+                .as_str()
+                .ok_or("Missing 'output'")?
+                .to_string();
+            let metadata: Option<ffmpeg::ChartMetadata> =
+                serde_json::from_value(args["metadata"].clone())
+                    .map_err(|e| format!("Invalid 'metadata': {}", e))?;
+            let options: Option<audio::ExportOptions> =
+                serde_json::from_value(args["options"].clone())
+                    .map_err(|e| format!("Invalid 'options': {}", e))?;
+            let app = APP_HANDLE
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or("App handle not available")?;
+            audio::export_audio(
+                app,
+                sounds,
+                timestamps,
+                length,
+                output,
+                metadata.unwrap_or_default(),
+                options.unwrap_or_default(),
+            )?;
+            Ok(Value::Null)
+        }
+        "validate_mix" => {
+            let sounds: Vec<audio::Sound> = serde_json::from_value(args["sounds"].clone())
+                .map_err(|e| format!("Invalid 'sounds': {}", e))?;
+            let timestamps: Vec<audio::Timestamp> =
+                serde_json::from_value(args["timestamps"].clone())
+                    .map_err(|e| format!("Invalid 'timestamps': {}", e))?;
+            let length = args["length"].as_f64().ok_or("Missing 'length'")?;
+            let problems = audio::validate_timeline(&sounds, &timestamps, length);
+            Ok(serde_json::to_value(problems).unwrap())
+        }
+        "get_waveform" => {
+            let path = args["path"].as_str().ok_or("Missing 'path'")?;
+            let buckets = args["buckets"].as_u64().ok_or("Missing 'buckets'")? as usize;
+            let start = args["start"].as_f64();
+            let end = args["end"].as_f64();
             let app = APP_HANDLE
                 .lock()
                 .unwrap()
                 .clone()
                 .ok_or("App handle not available")?;
-            audio::mix_audio(app, sounds, timestamps, length, output)?;
+            let waveform = audio::get_waveform(&app, path, buckets, start, end)?;
+            Ok(serde_json::to_value(waveform).unwrap())
+        }
+        "analyze_offset" => {
+            let path = args["path"].as_str().ok_or("Missing 'path'")?;
+            let note_times: Vec<f64> = serde_json::from_value(args["noteTimes"].clone())
+                .map_err(|e| format!("Invalid 'noteTimes': {}", e))?;
+            let options: Option<audio::OffsetOptions> =
+                serde_json::from_value(args["options"].clone())
+                    .map_err(|e| format!("Invalid 'options': {}", e))?;
+            let analysis = audio::analyze_offset(path, &note_times, &options.unwrap_or_default())?;
+            Ok(serde_json::to_value(analysis).unwrap())
+        }
+        "audio_preload" => {
+            let sounds: Vec<audio::Sound> = serde_json::from_value(args["sounds"].clone())
+                .map_err(|e| format!("Invalid 'sounds': {}", e))?;
+            let app = APP_HANDLE
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or("App handle not available")?;
+            let output = audio::preload(&app, sounds)?;
+            Ok(Value::String(output.to_string()))
+        }
+        "audio_unload" => {
+            let keys: Option<Vec<String>> = serde_json::from_value(args["keys"].clone())
+                .map_err(|e| format!("Invalid 'keys': {}", e))?;
+            audio::unload(keys.as_deref());
+            Ok(Value::Null)
+        }
+        "audio_play" => {
+            let key = args["key"].as_str().ok_or("Missing 'key'")?;
+            let at = args["at"].as_f64();
+            let volume = args["volume"].as_f64().unwrap_or(1.0) as f32;
+            let rate = args["rate"].as_f64().unwrap_or(1.0) as f32;
+            audio::play(key, at, volume, rate)?;
+            Ok(Value::Null)
+        }
+        "audio_stop" => {
+            audio::stop(args["key"].as_str());
+            Ok(Value::Null)
+        }
+        "audio_seek" => {
+            let time = args["time"].as_f64().ok_or("Missing 'time'")?;
+            audio::seek(time);
+            Ok(Value::Null)
+        }
+        "generate_calibration_video" => {
+            let app = APP_HANDLE
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or("App handle not available")?;
+            let output = args["output"].as_str().ok_or("Missing 'output'")?;
+            let options: Option<calibration::CalibrationOptions> =
+                serde_json::from_value(args["options"].clone())
+                    .map_err(|e| format!("Invalid 'options': {}", e))?;
+            let metadata = calibration::generate_calibration_video(
+                app,
+                output.to_string(),
+                options.unwrap_or_default(),
+            )?;
+            Ok(Value::String(metadata))
+        }
+        "get_audio_cache_info" => {
+            let app = APP_HANDLE
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or("App handle not available")?;
+            Ok(serde_json::to_value(audio::audio_cache_info(&app)).unwrap())
+        }
+        "begin_blob" => {
+            let id = args["id"].as_str().ok_or("Missing 'id'")?;
+            let size = args["size"].as_u64().ok_or("Missing 'size'")?;
+            blob::begin(id, size)?;
+            Ok(Value::Null)
+        }
+        "release_blob" => {
+            let id = args["id"].as_str().ok_or("Missing 'id'")?;
+            blob::release(id)?;
+            Ok(Value::Null)
+        }
+        "clear_audio_cache" => {
+            let app = APP_HANDLE
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or("App handle not available")?;
+            audio::clear_audio_cache(&app)?;
             Ok(Value::Null)
         }
         "console_log" => {
@@ -572,10 +779,15 @@ fn dispatch_command(command: &str, args: &Value) -> Result<Value, String> {
         }
         "fs_write_file" => {
             let path = args["path"].as_str().ok_or("Missing 'path'")?;
-            let data_b64 = args["dataBase64"].as_str().ok_or("Missing 'dataBase64'")?;
-            let data = STANDARD
-                .decode(data_b64)
-                .map_err(|e| format!("Invalid base64: {}", e))?;
+            let data = match args["blobId"].as_str() {
+                Some(id) => blob::read(id)?,
+                None => {
+                    let data_b64 = args["dataBase64"].as_str().ok_or("Missing 'dataBase64'")?;
+                    STANDARD
+                        .decode(data_b64)
+                        .map_err(|e| format!("Invalid base64: {}", e))?
+                }
+            };
             let append = args["append"].as_bool().unwrap_or(false);
             if append {
                 use std::fs::OpenOptions;
@@ -587,8 +799,7 @@ fn dispatch_command(command: &str, args: &Value) -> Result<Value, String> {
                 file.write_all(&data)
                     .map_err(|e| format!("Failed to append to file: {}", e))?;
             } else {
-                std::fs::write(path, &data)
-                    .map_err(|e| format!("Failed to write file: {}", e))?;
+                std::fs::write(path, &data).map_err(|e| format!("Failed to write file: {}", e))?;
             }
             Ok(Value::Null)
         }